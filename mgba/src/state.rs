@@ -5,6 +5,8 @@ pub struct State(pub(super) Box<mgba_sys::GBASerializedState>);
 unsafe impl Send for State {}
 
 impl State {
+    pub const SIZE: usize = std::mem::size_of::<mgba_sys::GBASerializedState>();
+
     pub fn rom_title(&self) -> String {
         let title = unsafe { &*(&self.0.title as *const [std::os::raw::c_char] as *const [u8]) };
         let cstr = match std::ffi::CString::new(title) {
@@ -64,3 +66,10 @@ impl State {
         }
     }
 }
+
+impl Default for State {
+    // All zeroes: not something a core can be loaded from, but fine for anything that only stores states.
+    fn default() -> Self {
+        Self::from_slice(&vec![0u8; Self::SIZE])
+    }
+}
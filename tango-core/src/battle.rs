@@ -6,43 +6,109 @@ use crate::fastforwarder;
 use crate::game;
 use crate::hooks;
 use crate::input;
+use crate::ipc;
+use crate::negotiation;
 use crate::protocol;
 use crate::replay;
+use crate::spectator;
 
 #[derive(Clone, Debug)]
 pub struct Settings {
     pub ice_servers: Vec<String>,
     pub matchmaking_connect_addr: String,
     pub session_id: String,
+    pub nickname: String,
     pub replays_path: std::path::PathBuf,
-    pub replay_metadata: Vec<u8>,
     pub match_type: u16,
-    pub input_delay: u32,
+    pub input_delay: Option<u32>,
+    pub replay_telemetry: bool,
+    pub replay_signing_key_path: Option<std::path::PathBuf>,
+    pub spectator_listen_addr: Option<String>,
+    pub spectator_token: Option<String>,
+    pub best_of: Option<u32>,
+    pub max_queue_length: u32,
+    pub input_predictor: Option<ipc::InputPredictor>,
+    pub compatible_games: Vec<Vec<String>>,
+    pub negotiation_timeouts: negotiation::Timeouts,
 }
 
+// The remote sent more inputs than the negotiated queue capacity allows it to, so it isn't respecting backpressure.
+#[derive(Debug)]
+pub struct InputQueueOverflow {
+    pub uncommittable: usize,
+    pub max_queue_length: usize,
+}
+
+impl std::fmt::Display for InputQueueOverflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "remote overflowed our input buffer: {} of its inputs were already waiting on ours, at most {} may be",
+            self.uncommittable, self.max_queue_length
+        )
+    }
+}
+
+impl std::error::Error for InputQueueOverflow {}
+
 pub struct RoundState {
     pub number: u8,
     pub round: Option<Round>,
     pub won_last_round: bool,
+    pub score: ipc::Score,
+    match_replay_writer: replay::MatchWriter,
 }
 
 impl RoundState {
-    pub async fn end_round(&mut self) -> anyhow::Result<()> {
-        match self.round.take() {
+    pub async fn end_round(&mut self) -> anyhow::Result<Option<ipc::RoundEnded>> {
+        let winner = match self.round.take() {
             Some(mut round) => {
-                round
+                let w = round
                     .replay_writer
                     .take()
                     .unwrap()
                     .finish()
                     .expect("finish");
+                self.match_replay_writer.end_round(w, round.winner())?;
+                if let Some(spectator_broadcaster) = &round.spectator_broadcaster {
+                    spectator_broadcaster.end_round();
+                }
+                let (predictions, mispredictions) = round.prediction_stats();
+                log::info!(
+                    "remote input mispredictions: {}/{}",
+                    mispredictions,
+                    predictions
+                );
+                round.won.map(|won| {
+                    if won {
+                        ipc::Side::Local
+                    } else {
+                        ipc::Side::Remote
+                    }
+                })
             }
             None => {
-                return Ok(());
+                return Ok(None);
             }
+        };
+
+        // A round that ends without either side winning is a draw.
+        match winner {
+            Some(ipc::Side::Local) => self.score.local_wins += 1,
+            Some(ipc::Side::Remote) => self.score.remote_wins += 1,
+            None => self.score.draws += 1,
         }
-        log::info!("round ended");
-        Ok(())
+        log::info!(
+            "round ended: winner = {:?}, score = {:?}",
+            winner,
+            self.score
+        );
+
+        Ok(Some(ipc::RoundEnded {
+            round_number: self.number,
+            winner,
+            score: self.score.clone(),
+        }))
     }
 }
 
@@ -50,10 +116,20 @@ pub struct Match {
     audio_supported_config: cpal::SupportedStreamConfig,
     rom_path: std::path::PathBuf,
     hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
-    _peer_conn: datachannel_wrapper::PeerConnection,
+    peer_conn: tokio::sync::Mutex<datachannel_wrapper::PeerConnection>,
     dc_rx: tokio::sync::Mutex<datachannel_wrapper::DataChannelReceiver>,
     dc_tx: tokio::sync::Mutex<datachannel_wrapper::DataChannelSender>,
     rng: tokio::sync::Mutex<rand_pcg::Mcg128Xsl64>,
+    remote_nickname: String,
+    remote_game: negotiation::GameInfo,
+    input_delay: u32,
+    max_queue_length: u32,
+    remote_input_notify: tokio::sync::Notify,
+    said_goodbye: std::sync::atomic::AtomicBool,
+    match_ended: std::sync::atomic::AtomicBool,
+    remote_left: tokio_util::sync::CancellationToken,
+    replay_signing_key: Option<std::sync::Arc<ed25519_dalek::Keypair>>,
+    spectator_broadcaster: Option<spectator::Broadcaster>,
     settings: Settings,
     is_offerer: bool,
     round_state: tokio::sync::Mutex<RoundState>,
@@ -61,76 +137,47 @@ pub struct Match {
     remote_init_receiver: tokio::sync::Mutex<tokio::sync::mpsc::Receiver<protocol::Init>>,
     primary_thread_handle: mgba::thread::Handle,
     audio_mux: audio::mux_stream::MuxStream,
+    ipc_client: ipc::Client,
 }
 
-#[derive(Debug)]
-pub enum NegotiationError {
-    ExpectedHello,
-    ExpectedHola,
-    IdenticalCommitment,
-    ProtocolVersionMismatch,
-    MatchTypeMismatch,
-    IncompatibleGames,
-    InvalidCommitment,
-    Other(anyhow::Error),
-}
-
-impl From<anyhow::Error> for NegotiationError {
-    fn from(err: anyhow::Error) -> Self {
-        NegotiationError::Other(err)
-    }
-}
-
-impl From<datachannel_wrapper::Error> for NegotiationError {
-    fn from(err: datachannel_wrapper::Error) -> Self {
-        NegotiationError::Other(err.into())
-    }
-}
-
-impl From<std::io::Error> for NegotiationError {
-    fn from(err: std::io::Error) -> Self {
-        NegotiationError::Other(err.into())
+// A state always decompresses to exactly its own size, so we don't read any further than that from the remote.
+fn decode_committed_state(compressed: &[u8]) -> anyhow::Result<mgba::state::State> {
+    let mut raw = vec![];
+    std::io::Read::read_to_end(
+        &mut std::io::Read::take(
+            zstd::stream::read::Decoder::new(compressed)?,
+            mgba::state::State::SIZE as u64 + 1,
+        ),
+        &mut raw,
+    )?;
+    if raw.len() != mgba::state::State::SIZE {
+        anyhow::bail!(
+            "committed state does not decompress to {} bytes",
+            mgba::state::State::SIZE
+        );
     }
+    Ok(mgba::state::State::from_slice(&raw))
 }
 
-impl std::fmt::Display for NegotiationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            NegotiationError::ExpectedHello => write!(f, "expected hello"),
-            NegotiationError::ExpectedHola => write!(f, "expected hola"),
-            NegotiationError::IdenticalCommitment => write!(f, "identical commitment"),
-            NegotiationError::ProtocolVersionMismatch => write!(f, "protocol version mismatch"),
-            NegotiationError::MatchTypeMismatch => write!(f, "match type mismatch"),
-            NegotiationError::IncompatibleGames => write!(f, "game mismatch"),
-            NegotiationError::InvalidCommitment => write!(f, "invalid commitment"),
-            NegotiationError::Other(e) => write!(f, "other error: {}", e),
-        }
-    }
-}
+pub const DEFAULT_MAX_QUEUE_LENGTH: u32 = 120;
+const REPLAY_KEYFRAME_INTERVAL: u32 = 600;
+const RESUME_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 
-impl std::error::Error for NegotiationError {}
+// How long we give the remote to see our goodbye before we close the connection on it anyway.
+const GOODBYE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
 
-pub enum NegotiationFailure {
-    ProtocolVersionMismatch,
-    MatchTypeMismatch,
-    IncompatibleGames,
-    Unknown,
-}
+// If the remote sends nothing for this long while our input queue is full, we give up on the match.
+const BACKPRESSURE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+const REPLAY_STATE_HASH_INTERVAL: u32 = 60;
 
-pub enum NegotiationStatus {
-    Ready,
-    NotReady(NegotiationProgress),
-    Failed(NegotiationFailure),
-}
+// EWRAM is hashed at committed ticks that are multiples of this and compared with the remote's. Both sides hash it as player 0 sees it, so player 1 re-simulates the match from player 0's perspective to do so.
+const LIVE_STATE_HASH_INTERVAL: u32 = 60;
 
-#[derive(Clone, Debug)]
-pub enum NegotiationProgress {
-    NotStarted,
-    Signalling,
-    Handshaking,
-}
+// How many hashes we hold on to while waiting for the other side's hash for the same tick.
+const MAX_PENDING_STATE_HASHES: usize = 8;
 
-const MAX_QUEUE_LENGTH: usize = 120;
+// If we roll back more than this many ticks on average between turns, input delay is raised by one.
+const MAX_MEAN_ROLLBACK: usize = 2;
 
 impl Match {
     pub fn new(
@@ -141,41 +188,111 @@ impl Match {
         peer_conn: datachannel_wrapper::PeerConnection,
         dc: datachannel_wrapper::DataChannel,
         mut rng: rand_pcg::Mcg128Xsl64,
+        rng_commitment: &[u8],
+        remote_nickname: String,
+        remote_game: negotiation::GameInfo,
+        input_delay: u32,
+        max_queue_length: u32,
         is_offerer: bool,
         primary_thread_handle: mgba::thread::Handle,
+        ipc_client: ipc::Client,
         settings: Settings,
-    ) -> Self {
+    ) -> anyhow::Result<Self> {
         let (remote_init_sender, remote_init_receiver) = tokio::sync::mpsc::channel(1);
         let (dc_rx, dc_tx) = dc.split();
         let did_polite_win_last_round = rng.gen::<bool>();
-        Self {
+
+        let mut replay_filename = settings.replays_path.clone();
+        replay_filename.push("match.tangoreplay");
+        let replay_filename = std::path::Path::new(&replay_filename);
+        let replay_file = std::fs::File::create(&replay_filename)?;
+        log::info!("opened replay: {}", replay_filename.display());
+        let match_replay_writer = replay::MatchWriter::new(Box::new(replay_file), rng_commitment)?;
+
+        let replay_signing_key = settings
+            .replay_signing_key_path
+            .as_ref()
+            .map(|path| replay::load_or_generate_signing_key(path).map(std::sync::Arc::new))
+            .transpose()?;
+
+        let spectator_broadcaster =
+            match (&settings.spectator_listen_addr, &settings.spectator_token) {
+                (Some(spectator_listen_addr), Some(spectator_token)) => {
+                    let spectator_broadcaster =
+                        spectator::Broadcaster::new(spectator_token.clone());
+                    {
+                        let spectator_broadcaster = spectator_broadcaster.clone();
+                        let spectator_listen_addr = spectator_listen_addr.clone();
+                        tokio::task::spawn(async move {
+                            if let Err(e) =
+                                spectator_broadcaster.listen(&spectator_listen_addr).await
+                            {
+                                log::warn!("spectator listener ended: {}", e);
+                            }
+                        });
+                    }
+                    Some(spectator_broadcaster)
+                }
+                (Some(_), None) => {
+                    // Anyone who can reach the address could watch otherwise.
+                    log::warn!("no spectator token set, not listening for spectators");
+                    None
+                }
+                (None, _) => None,
+            };
+
+        Ok(Self {
             audio_supported_config,
             rom_path,
             hooks,
-            _peer_conn: peer_conn,
+            peer_conn: tokio::sync::Mutex::new(peer_conn),
             dc_rx: tokio::sync::Mutex::new(dc_rx),
             dc_tx: tokio::sync::Mutex::new(dc_tx),
             rng: tokio::sync::Mutex::new(rng),
+            remote_nickname,
+            remote_game,
+            input_delay,
+            max_queue_length,
+            remote_input_notify: tokio::sync::Notify::new(),
+            said_goodbye: std::sync::atomic::AtomicBool::new(false),
+            match_ended: std::sync::atomic::AtomicBool::new(false),
+            remote_left: tokio_util::sync::CancellationToken::new(),
+            replay_signing_key,
+            spectator_broadcaster,
             settings,
             round_state: tokio::sync::Mutex::new(RoundState {
                 number: 0,
                 round: None,
                 won_last_round: did_polite_win_last_round == is_offerer,
+                score: ipc::Score::default(),
+                match_replay_writer,
             }),
             is_offerer,
             remote_init_sender,
             remote_init_receiver: tokio::sync::Mutex::new(remote_init_receiver),
             audio_mux,
             primary_thread_handle,
-        }
+            ipc_client,
+        })
     }
 
+    // Returns Ok once either side has said goodbye and the data channel has closed.
     pub async fn run(&self) -> anyhow::Result<()> {
         let mut dc_rx = self.dc_rx.lock().await;
+        let mut remote_said_goodbye = false;
         loop {
             match protocol::Packet::deserialize(
                 match dc_rx.receive().await {
-                    None => break,
+                    None if remote_said_goodbye
+                        || self.said_goodbye.load(std::sync::atomic::Ordering::SeqCst) =>
+                    {
+                        self.remote_left.cancel();
+                        return Ok(());
+                    }
+                    None => {
+                        self.resume(&mut dc_rx).await?;
+                        continue;
+                    }
                     Some(buf) => buf,
                 }
                 .as_slice(),
@@ -216,9 +333,8 @@ impl Match {
                         Some(b) => b,
                     };
 
-                    if !round.can_add_remote_input() {
-                        anyhow::bail!("remote overflowed our input buffer");
-                    }
+                    // The remote should have stopped sending well before this, so only a misbehaving peer gets here.
+                    round.check_remote_input_capacity()?;
 
                     round.add_remote_input(input::Input {
                         local_tick: input.local_tick,
@@ -227,11 +343,271 @@ impl Match {
                         custom_screen_state: input.custom_screen_state as u8,
                         turn: input.turn,
                     });
+                    self.remote_input_notify.notify_one();
+                }
+                protocol::Packet::StateCommitted(state_committed) => {
+                    let mut round_state = self.round_state.lock().await;
+
+                    if state_committed.round_number != round_state.number {
+                        log::info!("round number mismatch, dropping committed state");
+                        continue;
+                    }
+
+                    let round = match &mut round_state.round {
+                        None => {
+                            log::info!("no round in progress, dropping committed state");
+                            continue;
+                        }
+                        Some(b) => b,
+                    };
+
+                    round.set_remote_committed_state(decode_committed_state(
+                        state_committed.state.as_slice(),
+                    )?);
+                }
+                protocol::Packet::InputDelayChanged(input_delay_changed) => {
+                    let mut round_state = self.round_state.lock().await;
+
+                    if input_delay_changed.round_number != round_state.number {
+                        log::info!("round number mismatch, dropping input delay change");
+                        continue;
+                    }
+
+                    let round = match &mut round_state.round {
+                        None => {
+                            log::info!("no round in progress, dropping input delay change");
+                            continue;
+                        }
+                        Some(b) => b,
+                    };
+
+                    round.set_pending_remote_delay(
+                        input_delay_changed.tick,
+                        input_delay_changed.input_delay,
+                    );
+                }
+                protocol::Packet::StateHash(state_hash) => {
+                    let mut round_state = self.round_state.lock().await;
+
+                    if state_hash.round_number != round_state.number {
+                        log::info!("round number mismatch, dropping state hash");
+                        continue;
+                    }
+
+                    let round = match &mut round_state.round {
+                        None => {
+                            log::info!("no round in progress, dropping state hash");
+                            continue;
+                        }
+                        Some(b) => b,
+                    };
+
+                    round.add_remote_state_hash(state_hash.tick, state_hash.hash);
+                    if let Some(desync) = round.check_state_hashes() {
+                        return Err(self.report_desync(state_hash.round_number, desync).await);
+                    }
+                }
+                protocol::Packet::Forfeit(forfeit) => {
+                    let mut round_state = self.round_state.lock().await;
+
+                    if forfeit.round_number != round_state.number {
+                        log::info!("round number mismatch, dropping forfeit");
+                        continue;
+                    }
+
+                    let round = match &mut round_state.round {
+                        None => {
+                            log::info!("no round in progress, dropping forfeit");
+                            continue;
+                        }
+                        Some(b) => b,
+                    };
+
+                    // The emulator is still in the middle of the battle when this happens and will keep running it out: the hooks check that a round is active before touching it, so they just let it play out without us.
+                    log::info!("remote forfeited round {}", forfeit.round_number);
+                    let remote_player_index = round.remote_player_index();
+                    round.write_forfeit(remote_player_index);
+                    round.set_won(true);
+                    round_state.won_last_round = true;
+                    let round_ended = round_state.end_round().await?;
+                    drop(round_state);
+
+                    if let Err(e) = self
+                        .ipc_client
+                        .send_notification(ipc::Notification::RemoteForfeited(
+                            ipc::RemoteForfeited {
+                                round_number: forfeit.round_number,
+                            },
+                        ))
+                        .await
+                    {
+                        log::warn!("failed to send remote forfeited notification: {}", e);
+                    }
+                    if let Some(round_ended) = round_ended {
+                        self.report_round_ended(round_ended).await;
+                    }
+                    self.report_match_ended(ipc::MatchEndReason::RemoteForfeited)
+                        .await;
+                }
+                protocol::Packet::Goodbye(goodbye) => {
+                    log::info!("remote said goodbye: {:?}", goodbye.reason);
+                    remote_said_goodbye = true;
+                    self.remote_left.cancel();
+
+                    {
+                        let mut round_state = self.round_state.lock().await;
+                        if let Some(round) = round_state.round.as_mut() {
+                            let remote_player_index = round.remote_player_index();
+                            round.write_goodbye(remote_player_index, &goodbye.reason);
+                        }
+                    }
+
+                    let reason = match goodbye.reason {
+                        protocol::GoodbyeReason::MatchEnded => ipc::GoodbyeReason::MatchEnded,
+                        protocol::GoodbyeReason::Quit => ipc::GoodbyeReason::Quit,
+                    };
+                    if let Err(e) = self
+                        .ipc_client
+                        .send_notification(ipc::Notification::RemoteLeft(ipc::RemoteLeft {
+                            reason,
+                        }))
+                        .await
+                    {
+                        log::warn!("failed to send remote left notification: {}", e);
+                    }
+                    self.report_match_ended(ipc::MatchEndReason::RemoteLeft(reason))
+                        .await;
                 }
                 p => anyhow::bail!("unknown packet: {:?}", p),
             }
         }
+    }
+
+    async fn resume(
+        &self,
+        dc_rx: &mut datachannel_wrapper::DataChannelReceiver,
+    ) -> anyhow::Result<()> {
+        log::warn!("data channel closed, attempting to resume session");
+
+        // Nothing is locked while we reconnect, so the game keeps running: sends on the old data channel fail in the meantime, and whatever didn't make it is resent once we're through.
+        let (peer_conn, dc) = tokio::time::timeout(
+            RESUME_TIMEOUT,
+            negotiation::reconnect(
+                &self.settings.matchmaking_connect_addr,
+                &self.settings.session_id,
+                &self.settings.ice_servers,
+                &self.settings.negotiation_timeouts,
+            ),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out reconnecting"))??;
+        *self.peer_conn.lock().await = peer_conn;
+        let (mut new_dc_rx, mut new_dc_tx) = dc.split();
+
+        let (round_number, last_received_tick, input_delay) = {
+            let round_state = self.round_state.lock().await;
+            (
+                round_state.number,
+                round_state
+                    .round
+                    .as_ref()
+                    .and_then(|round| round.last_received_remote_tick()),
+                round_state
+                    .round
+                    .as_ref()
+                    .map_or(self.input_delay, |round| round.local_delay()),
+            )
+        };
+
+        new_dc_tx
+            .send(
+                protocol::Packet::Resume(protocol::Resume {
+                    round_number,
+                    last_received_tick,
+                    input_delay,
+                })
+                .serialize()?
+                .as_slice(),
+            )
+            .await?;
+
+        let resume = match protocol::Packet::deserialize(
+            match tokio::time::timeout(RESUME_TIMEOUT, new_dc_rx.receive())
+                .await
+                .map_err(|_| anyhow::anyhow!("timed out waiting for resume"))?
+            {
+                Some(buf) => buf,
+                None => anyhow::bail!("data channel closed while resuming"),
+            }
+            .as_slice(),
+        )? {
+            protocol::Packet::Resume(resume) => resume,
+            p => anyhow::bail!("expected resume, got: {:?}", p),
+        };
+
+        if resume.round_number != round_number {
+            anyhow::bail!(
+                "round number mismatch while resuming: ours = {}, theirs = {}",
+                round_number,
+                resume.round_number
+            );
+        }
+
+        // Same order as the facade takes them in, and held until the new data channel is in place so nothing new goes out ahead of what we resend.
+        let mut round_state = self.round_state.lock().await;
+        let mut dc_tx = self.dc_tx.lock().await;
+
+        if round_state.number != round_number {
+            anyhow::bail!(
+                "round changed while resuming: was {}, now {}",
+                round_number,
+                round_state.number
+            );
+        }
+
+        if let Some(round) = round_state.round.as_mut() {
+            round.set_remote_delay(resume.input_delay);
+
+            // The remote may have missed our last delay change, and applying it again is harmless if it didn't.
+            if let Some((tick, input_delay)) = round.last_local_delay_change() {
+                new_dc_tx
+                    .send(
+                        protocol::Packet::InputDelayChanged(protocol::InputDelayChanged {
+                            round_number,
+                            tick,
+                            input_delay,
+                        })
+                        .serialize()?
+                        .as_slice(),
+                    )
+                    .await?;
+            }
 
+            // Anything past what the remote last received never made it across. We may have already committed some of it on our side if we had the remote's inputs for those ticks, so this comes from the unacknowledged inputs rather than the queue.
+            let inputs = round.unacked_local_inputs_after(resume.last_received_tick);
+            log::info!("resending {} unacknowledged inputs", inputs.len());
+            for input in inputs {
+                new_dc_tx
+                    .send(
+                        protocol::Packet::Input(protocol::Input {
+                            round_number,
+                            local_tick: input.local_tick,
+                            remote_tick: input.remote_tick,
+                            joyflags: input.joyflags,
+                            custom_screen_state: input.custom_screen_state,
+                            turn: input.turn,
+                        })
+                        .serialize()?
+                        .as_slice(),
+                    )
+                    .await?;
+            }
+        }
+
+        *dc_rx = new_dc_rx;
+        *dc_tx = new_dc_tx;
+
+        log::info!("session resumed");
         Ok(())
     }
 
@@ -239,6 +615,13 @@ impl Match {
         self.round_state.lock().await
     }
 
+    // Returns false if nothing arrived before the backpressure timeout.
+    pub async fn wait_for_remote_input(&self) -> bool {
+        tokio::time::timeout(BACKPRESSURE_TIMEOUT, self.remote_input_notify.notified())
+            .await
+            .is_ok()
+    }
+
     pub async fn receive_remote_init(&self) -> Option<protocol::Init> {
         let mut remote_init_receiver = self.remote_init_receiver.lock().await;
         remote_init_receiver.recv().await
@@ -294,6 +677,212 @@ impl Match {
         Ok(())
     }
 
+    pub async fn send_input_delay_changed(
+        &self,
+        round_number: u8,
+        tick: u32,
+        input_delay: u32,
+    ) -> anyhow::Result<()> {
+        self.dc_tx
+            .lock()
+            .await
+            .send(
+                protocol::Packet::InputDelayChanged(protocol::InputDelayChanged {
+                    round_number,
+                    tick,
+                    input_delay,
+                })
+                .serialize()?
+                .as_slice(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_state_committed(
+        &self,
+        round_number: u8,
+        state: &mgba::state::State,
+    ) -> anyhow::Result<()> {
+        self.dc_tx
+            .lock()
+            .await
+            .send(
+                protocol::Packet::StateCommitted(protocol::StateCommitted {
+                    round_number,
+                    state: zstd::stream::encode_all(state.as_slice(), 3)?,
+                })
+                .serialize()?
+                .as_slice(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_state_hash(
+        &self,
+        round_number: u8,
+        tick: u32,
+        hash: u32,
+    ) -> anyhow::Result<()> {
+        self.dc_tx
+            .lock()
+            .await
+            .send(
+                protocol::Packet::StateHash(protocol::StateHash {
+                    round_number,
+                    tick,
+                    hash,
+                })
+                .serialize()?
+                .as_slice(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // Dumps our side of the desync for debugging and lets the frontend know, returning the error the match should end with.
+    pub async fn report_desync(&self, round_number: u8, desync: Desync) -> anyhow::Error {
+        let mut state_path = self.settings.replays_path.clone();
+        state_path.push(format!(
+            "desync-round{}-tick{}.state",
+            round_number, desync.tick
+        ));
+        let state_path = match std::fs::write(&state_path, desync.state.as_slice()) {
+            Ok(()) => {
+                log::info!("dumped desynced state: {}", state_path.display());
+                Some(state_path)
+            }
+            Err(e) => {
+                log::warn!("failed to dump desynced state: {}", e);
+                None
+            }
+        };
+
+        if let Err(e) = self
+            .ipc_client
+            .send_notification(ipc::Notification::Desync(ipc::Desync {
+                round_number,
+                tick: desync.tick,
+                state_path: state_path.map(|p| p.to_string_lossy().to_string()),
+            }))
+            .await
+        {
+            log::warn!("failed to send desync notification: {}", e);
+        }
+
+        anyhow::anyhow!(
+            "desync detected at tick {}: local hash = {:08x}, remote hash = {:08x}",
+            desync.tick,
+            desync.local_hash,
+            desync.remote_hash
+        )
+    }
+
+    pub async fn report_round_ended(&self, round_ended: ipc::RoundEnded) {
+        if let Err(e) = self
+            .ipc_client
+            .send_notification(ipc::Notification::RoundEnded(round_ended))
+            .await
+        {
+            log::warn!("failed to send round ended notification: {}", e);
+        }
+    }
+
+    // Only the first report goes out: a remote leaving mid-round sends both a forfeit and a goodbye, and we may be ending the match ourselves at the same time.
+    pub async fn report_match_ended(&self, reason: ipc::MatchEndReason) {
+        if self
+            .match_ended
+            .swap(true, std::sync::atomic::Ordering::SeqCst)
+        {
+            return;
+        }
+
+        let score = self.round_state.lock().await.score.clone();
+        let winner = match score.local_wins.cmp(&score.remote_wins) {
+            std::cmp::Ordering::Greater => Some(ipc::Side::Local),
+            std::cmp::Ordering::Less => Some(ipc::Side::Remote),
+            std::cmp::Ordering::Equal => None,
+        };
+        log::info!(
+            "match ended: winner = {:?}, score = {:?}, reason = {:?}",
+            winner,
+            score,
+            reason
+        );
+        if let Err(e) = self
+            .ipc_client
+            .send_notification(ipc::Notification::MatchEnded(ipc::MatchEnded {
+                winner,
+                score,
+                reason,
+            }))
+            .await
+        {
+            log::warn!("failed to send match ended notification: {}", e);
+        }
+    }
+
+    // Waits until the remote has either said goodbye or closed the data channel after ours, so it can't miss our goodbye when we close the connection.
+    pub async fn wait_for_remote_left(&self) {
+        if tokio::time::timeout(GOODBYE_TIMEOUT, self.remote_left.cancelled())
+            .await
+            .is_err()
+        {
+            log::warn!("remote didn't leave in time, closing the connection anyway");
+        }
+    }
+
+    // Lets the remote know we're going away, so it doesn't try to resume. Leaving in the middle of a round forfeits it.
+    pub async fn shutdown(&self, reason: protocol::GoodbyeReason) -> anyhow::Result<()> {
+        let mut round_state = self.round_state.lock().await;
+        let mut dc_tx = self.dc_tx.lock().await;
+
+        // From here on, the data channel closing means the remote is done with us, not that we should resume.
+        self.said_goodbye
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+
+        let round_number = round_state.number;
+        if let Some(round) = round_state.round.as_mut() {
+            log::warn!("leaving while a round is still in progress, forfeiting");
+            let local_player_index = round.local_player_index();
+            round.write_forfeit(local_player_index);
+            round.write_goodbye(local_player_index, &reason);
+            round.set_won(false);
+            round_state.won_last_round = false;
+            round_state.end_round().await?;
+
+            dc_tx
+                .send(
+                    protocol::Packet::Forfeit(protocol::Forfeit { round_number })
+                        .serialize()?
+                        .as_slice(),
+                )
+                .await?;
+        }
+
+        dc_tx
+            .send(
+                protocol::Packet::Goodbye(protocol::Goodbye { reason })
+                    .serialize()?
+                    .as_slice(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    // With no best-of set, the match goes on until the players leave the battle menu.
+    pub fn is_set_decided(&self, score: &ipc::Score) -> bool {
+        let best_of = match self.settings.best_of {
+            Some(best_of) => best_of,
+            None => {
+                return false;
+            }
+        };
+        std::cmp::max(score.local_wins, score.remote_wins) * 2 > best_of
+            || score.local_wins + score.remote_wins + score.draws >= best_of
+    }
+
     pub async fn lock_rng(&self) -> tokio::sync::MutexGuard<'_, rand_pcg::Mcg128Xsl64> {
         self.rng.lock().await
     }
@@ -306,6 +895,41 @@ impl Match {
         self.is_offerer
     }
 
+    pub fn replay_metadata(
+        &self,
+        round_number: u8,
+        local_input_delay: u32,
+        remote_input_delay: u32,
+        local_state: &mgba::state::State,
+    ) -> replay::Metadata {
+        replay::Metadata {
+            version: replay::METADATA_VERSION,
+            match_type: self.settings.match_type,
+            round_number,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+            local_player: replay::PlayerMetadata {
+                nickname: self.settings.nickname.clone(),
+                rom_title: Some(local_state.rom_title()),
+                rom_crc32: Some(local_state.rom_crc32()),
+                input_delay: local_input_delay,
+                public_key: self
+                    .replay_signing_key
+                    .as_ref()
+                    .map(|k| hex::encode(k.public.as_bytes())),
+            },
+            remote_player: replay::PlayerMetadata {
+                nickname: self.remote_nickname.clone(),
+                rom_title: Some(self.remote_game.title.clone()),
+                rom_crc32: Some(self.remote_game.rom_crc32),
+                input_delay: remote_input_delay,
+                public_key: None,
+            },
+            legacy: None,
+        }
+    }
+
     pub async fn start_round(&self, core: mgba::core::CoreMutRef<'_>) -> anyhow::Result<()> {
         let mut round_state = self.round_state.lock().await;
         round_state.number += 1;
@@ -314,12 +938,6 @@ impl Match {
             "starting round: local_player_index = {}",
             local_player_index
         );
-        let mut replay_filename = self.settings.replays_path.clone();
-        replay_filename.push(format!("round{}.tangoreplay", round_state.number));
-        let replay_filename = std::path::Path::new(&replay_filename);
-        let replay_file = std::fs::File::create(&replay_filename)?;
-        log::info!("opened replay: {}", replay_filename.display());
-
         log::info!("starting audio core");
         let mut audio_core = mgba::core::Core::new_gba("tango")?;
         let audio_save_state_holder = std::sync::Arc::new(parking_lot::Mutex::new(None));
@@ -361,11 +979,51 @@ impl Match {
 
         log::info!("preparing round state");
 
+        let mut fastforwarder = fastforwarder::Fastforwarder::new(
+            &self.rom_path,
+            self.hooks,
+            local_player_index,
+            match self.settings.input_predictor {
+                Some(ipc::InputPredictor::Held) => Box::new(input::HeldInputPredictor),
+                Some(ipc::InputPredictor::AB) | None => Box::new(input::ABInputPredictor),
+            },
+        )?;
+        let canonical_perspective = if local_player_index == 0 {
+            fastforwarder.set_state_hash_interval(LIVE_STATE_HASH_INTERVAL);
+            None
+        } else {
+            let mut fastforwarder = fastforwarder::Fastforwarder::new(
+                &self.rom_path,
+                self.hooks,
+                0,
+                Box::new(input::HeldInputPredictor),
+            )?;
+            fastforwarder.set_state_hash_interval(LIVE_STATE_HASH_INTERVAL);
+            Some(CanonicalPerspective {
+                fastforwarder,
+                state: None,
+                pending_input_pairs: vec![],
+            })
+        };
+
         let (state_committed_tx, state_committed_rx) = tokio::sync::oneshot::channel();
         round_state.round = Some(Round {
             local_player_index,
-            iq: input::PairQueue::new(MAX_QUEUE_LENGTH, self.settings.input_delay),
+            iq: input::PairQueue::new(self.max_queue_length as usize, self.input_delay),
+            max_queue_length: self.max_queue_length as usize,
             remote_delay: 0,
+            last_received_remote_tick: None,
+            unacked_local_inputs: std::collections::VecDeque::new(),
+            pending_remote_delay: None,
+            last_local_delay_change: None,
+            dynamic_input_delay: self.settings.input_delay.is_none(),
+            skip_local_input: false,
+            outgoing_state_hashes: vec![],
+            local_state_hashes: std::collections::VecDeque::new(),
+            remote_state_hashes: std::collections::VecDeque::new(),
+            rollback_ticks: 0,
+            rollback_samples: 0,
+            max_rollback: 0,
             is_accepting_input: false,
             last_committed_remote_input: input::Input {
                 local_tick: 0,
@@ -378,17 +1036,20 @@ impl Match {
             state_committed_tx: Some(state_committed_tx),
             state_committed_rx: Some(state_committed_rx),
             committed_state: None,
+            remote_committed_state: None,
             local_pending_turn: None,
+            won: None,
+            spectator_broadcaster: self.spectator_broadcaster.clone(),
             replay_writer: Some(replay::Writer::new(
-                Box::new(replay_file),
-                &self.settings.replay_metadata,
+                round_state.match_replay_writer.begin_round()?,
                 local_player_index,
+                REPLAY_KEYFRAME_INTERVAL,
+                REPLAY_STATE_HASH_INTERVAL,
+                self.settings.replay_telemetry,
+                self.replay_signing_key.clone(),
             )?),
-            fastforwarder: fastforwarder::Fastforwarder::new(
-                &self.rom_path,
-                self.hooks,
-                local_player_index,
-            )?,
+            fastforwarder,
+            canonical_perspective,
             audio_save_state_holder,
             _audio_core_thread: audio_core_thread,
             _audio_core_mux_handle: audio_core_mux_handle,
@@ -399,6 +1060,21 @@ impl Match {
     }
 }
 
+pub struct Desync {
+    pub tick: u32,
+    pub local_hash: u32,
+    pub remote_hash: u32,
+    pub state: mgba::state::State,
+}
+
+// Player 0's view of the match, as re-simulated by player 1 from player 0's initial state and the same committed inputs.
+struct CanonicalPerspective {
+    fastforwarder: fastforwarder::Fastforwarder,
+    state: Option<mgba::state::State>,
+    // Committed so far but not yet simulated, from player 0's side: this waits on player 0's initial state to arrive.
+    pending_input_pairs: Vec<input::Pair<input::Input>>,
+}
+
 struct LocalPendingTurn {
     marshaled: Vec<u8>,
     ticks_left: u8,
@@ -407,16 +1083,34 @@ struct LocalPendingTurn {
 pub struct Round {
     local_player_index: u8,
     iq: input::PairQueue<input::Input>,
+    max_queue_length: usize,
     remote_delay: u32,
+    last_received_remote_tick: Option<u32>,
+    // Local inputs we've sent that the remote hasn't acknowledged yet, committed or not.
+    unacked_local_inputs: std::collections::VecDeque<input::Input>,
+    pending_remote_delay: Option<(u32, u32)>,
+    last_local_delay_change: Option<(u32, u32)>,
+    dynamic_input_delay: bool,
+    skip_local_input: bool,
+    outgoing_state_hashes: Vec<(u32, u32)>,
+    local_state_hashes: std::collections::VecDeque<(u32, u32, mgba::state::State)>,
+    remote_state_hashes: std::collections::VecDeque<(u32, u32)>,
+    rollback_ticks: usize,
+    rollback_samples: usize,
+    max_rollback: usize,
     is_accepting_input: bool,
     last_committed_remote_input: input::Input,
     last_input: Option<input::Pair<input::Input>>,
     state_committed_tx: Option<tokio::sync::oneshot::Sender<()>>,
     state_committed_rx: Option<tokio::sync::oneshot::Receiver<()>>,
     committed_state: Option<mgba::state::State>,
+    remote_committed_state: Option<mgba::state::State>,
     local_pending_turn: Option<LocalPendingTurn>,
+    won: Option<bool>,
+    spectator_broadcaster: Option<spectator::Broadcaster>,
     replay_writer: Option<replay::Writer>,
     fastforwarder: fastforwarder::Fastforwarder,
+    canonical_perspective: Option<CanonicalPerspective>,
     audio_save_state_holder: std::sync::Arc<parking_lot::Mutex<Option<mgba::state::State>>>,
     primary_thread_handle: mgba::thread::Handle,
     _audio_core_thread: mgba::thread::Thread,
@@ -448,6 +1142,42 @@ impl Round {
                 .unwrap()
                 .write_state_placeholder()
                 .expect("write state");
+            if let Some(remote_state) = &self.remote_committed_state {
+                self.replay_writer
+                    .as_mut()
+                    .unwrap()
+                    .write_remote_state(remote_state)
+                    .expect("write remote state");
+            }
+            if let Some(spectator_broadcaster) = &self.spectator_broadcaster {
+                spectator_broadcaster.start_round(self.local_player_index, &state);
+            }
+        } else {
+            self.replay_writer
+                .as_mut()
+                .unwrap()
+                .offer_keyframe(&state)
+                .expect("write keyframe");
+            self.replay_writer
+                .as_mut()
+                .unwrap()
+                .offer_state_hash(&state)
+                .expect("write state hash");
+        }
+
+        self.advance_canonical_perspective();
+
+        // The fastforwarder hashes exactly at each interval boundary, even when a commit covers several of them.
+        let state_hashes = match &mut self.canonical_perspective {
+            Some(canonical_perspective) => canonical_perspective.fastforwarder.take_state_hashes(),
+            None => self.fastforwarder.take_state_hashes(),
+        };
+        for (tick, hash, state) in state_hashes {
+            self.local_state_hashes.push_back((tick, hash, state));
+            if self.local_state_hashes.len() > MAX_PENDING_STATE_HASHES {
+                self.local_state_hashes.pop_front();
+            }
+            self.outgoing_state_hashes.push((tick, hash));
         }
         self.committed_state = Some(state);
         if let Some(tx) = self.state_committed_tx.take() {
@@ -455,6 +1185,140 @@ impl Round {
         }
     }
 
+    fn advance_canonical_perspective(&mut self) {
+        let canonical_perspective = match &mut self.canonical_perspective {
+            Some(canonical_perspective) => canonical_perspective,
+            None => {
+                return;
+            }
+        };
+
+        let state = match (&canonical_perspective.state, &self.remote_committed_state) {
+            (Some(state), _) => state,
+            (None, Some(remote_state)) => remote_state,
+            (None, None) => {
+                return;
+            }
+        };
+
+        let input_pairs = std::mem::take(&mut canonical_perspective.pending_input_pairs);
+        let last_input_pair = match input_pairs.last() {
+            Some(ip) => ip.clone(),
+            None => {
+                return;
+            }
+        };
+
+        match canonical_perspective.fastforwarder.fastforward(
+            state,
+            &input_pairs,
+            last_input_pair.remote,
+            &[],
+        ) {
+            Ok((committed_state, _, _)) => {
+                canonical_perspective.state = Some(committed_state);
+            }
+            Err(e) => {
+                log::error!(
+                    "failed to simulate player 0's perspective, no longer checking for desyncs: {}",
+                    e
+                );
+                self.canonical_perspective = None;
+            }
+        }
+    }
+
+    pub fn write_forfeit(&mut self, player_index: u8) {
+        self.replay_writer
+            .as_mut()
+            .unwrap()
+            .write_forfeit(player_index)
+            .expect("write forfeit");
+    }
+
+    pub fn write_goodbye(&mut self, player_index: u8, reason: &protocol::GoodbyeReason) {
+        self.replay_writer
+            .as_mut()
+            .unwrap()
+            .write_goodbye(player_index, reason)
+            .expect("write goodbye");
+    }
+
+    pub fn take_outgoing_state_hashes(&mut self) -> Vec<(u32, u32)> {
+        std::mem::take(&mut self.outgoing_state_hashes)
+    }
+
+    pub fn add_remote_state_hash(&mut self, tick: u32, hash: u32) {
+        self.remote_state_hashes.push_back((tick, hash));
+        if self.remote_state_hashes.len() > MAX_PENDING_STATE_HASHES {
+            self.remote_state_hashes.pop_front();
+        }
+    }
+
+    pub fn check_state_hashes(&mut self) -> Option<Desync> {
+        let mut i = 0;
+        while i < self.remote_state_hashes.len() {
+            let (remote_tick, remote_hash) = self.remote_state_hashes[i];
+            let local_index = match self
+                .local_state_hashes
+                .iter()
+                .position(|(tick, _, _)| *tick == remote_tick)
+            {
+                Some(local_index) => local_index,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let (tick, local_hash, state) = self.local_state_hashes.remove(local_index).unwrap();
+            self.remote_state_hashes.remove(i);
+            if local_hash != remote_hash {
+                return Some(Desync {
+                    tick,
+                    local_hash,
+                    remote_hash,
+                    state,
+                });
+            }
+        }
+        None
+    }
+
+    pub fn write_replay_metadata(&mut self, metadata: &replay::Metadata) {
+        self.replay_writer
+            .as_mut()
+            .unwrap()
+            .write_metadata(metadata)
+            .expect("write metadata");
+    }
+
+    pub fn set_won(&mut self, did_win: bool) {
+        self.won = Some(did_win);
+    }
+
+    pub fn winner(&self) -> Option<u8> {
+        self.won.map(|won| {
+            if won {
+                self.local_player_index
+            } else {
+                self.remote_player_index()
+            }
+        })
+    }
+
+    pub fn set_remote_committed_state(&mut self, state: mgba::state::State) {
+        // If our own state hasn't been committed yet, this will be written out when it is.
+        if self.committed_state.is_some() {
+            self.replay_writer
+                .as_mut()
+                .unwrap()
+                .write_remote_state(&state)
+                .expect("write remote state");
+        }
+        self.remote_committed_state = Some(state);
+    }
+
     pub fn set_audio_save_state(&mut self, state: mgba::state::State) {
         *self.audio_save_state_holder.lock() = Some(state);
     }
@@ -479,6 +1343,79 @@ impl Round {
         self.remote_delay
     }
 
+    pub fn set_local_delay(&mut self, tick: u32, delay: u32) {
+        log::info!(
+            "local input delay changed: {} -> {}",
+            self.iq.local_delay(),
+            delay
+        );
+        self.iq.set_local_delay(delay);
+        self.last_local_delay_change = Some((tick, delay));
+    }
+
+    pub fn last_local_delay_change(&self) -> Option<(u32, u32)> {
+        self.last_local_delay_change
+    }
+
+    pub fn set_pending_remote_delay(&mut self, tick: u32, delay: u32) {
+        self.pending_remote_delay = Some((tick, delay));
+    }
+
+    pub fn apply_pending_remote_delay(&mut self, current_tick: u32) {
+        match self.pending_remote_delay {
+            Some((tick, delay)) if tick <= current_tick => {
+                log::info!(
+                    "remote input delay changed: {} -> {}",
+                    self.remote_delay,
+                    delay
+                );
+                self.remote_delay = delay;
+                self.pending_remote_delay = None;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn record_rollback(&mut self, ticks: usize) {
+        self.rollback_ticks += ticks;
+        self.rollback_samples += 1;
+        self.max_rollback = std::cmp::max(self.max_rollback, ticks);
+    }
+
+    // Growing the delay takes effect at the turn boundary itself, but shrinking it means skipping an input, which is deferred to the next tick without a marshaled turn.
+    pub fn plan_local_delay_change(&mut self) -> Option<u32> {
+        if !self.dynamic_input_delay || self.rollback_samples == 0 {
+            return None;
+        }
+
+        let mean_rollback = self.rollback_ticks / self.rollback_samples;
+        let max_rollback = self.max_rollback;
+        self.rollback_ticks = 0;
+        self.rollback_samples = 0;
+        self.max_rollback = 0;
+
+        let local_delay = self.local_delay();
+        if mean_rollback > MAX_MEAN_ROLLBACK
+            && local_delay < negotiation::MAX_AUTO_INPUT_DELAY
+            && self.local_queue_length() + 2 <= self.max_queue_length
+        {
+            Some(local_delay + 1)
+        } else if max_rollback == 0 && local_delay > negotiation::MIN_AUTO_INPUT_DELAY {
+            self.skip_local_input = true;
+            Some(local_delay - 1)
+        } else {
+            None
+        }
+    }
+
+    pub fn take_local_input_skip(&mut self) -> bool {
+        std::mem::replace(&mut self.skip_local_input, false)
+    }
+
+    pub fn prediction_stats(&self) -> (usize, usize) {
+        self.fastforwarder.prediction_stats()
+    }
+
     pub fn local_queue_length(&self) -> usize {
         self.iq.local_queue_length()
     }
@@ -507,39 +1444,99 @@ impl Round {
         &mut self,
     ) -> (Vec<input::Pair<input::Input>>, Vec<input::Input>) {
         let (input_pairs, left) = self.iq.consume_and_peek_local();
+
+        let mut last_committed_remote_input = &self.last_committed_remote_input;
+        for ip in &input_pairs {
+            self.fastforwarder
+                .record_prediction(last_committed_remote_input, ip);
+            last_committed_remote_input = &ip.remote;
+        }
+
         if let Some(last) = input_pairs.last() {
             self.last_committed_remote_input = last.remote.clone();
         }
 
+        if let Some(canonical_perspective) = &mut self.canonical_perspective {
+            canonical_perspective
+                .pending_input_pairs
+                .extend(input_pairs.iter().cloned().map(|ip| input::Pair {
+                    local: ip.remote,
+                    remote: ip.local,
+                }));
+        }
+
         for ip in &input_pairs {
             self.replay_writer
                 .as_mut()
                 .unwrap()
                 .write_input(self.local_player_index, ip)
                 .expect("write input");
+            if let Some(spectator_broadcaster) = &self.spectator_broadcaster {
+                spectator_broadcaster.send_input(ip);
+            }
         }
 
         (input_pairs, left)
     }
 
+    // Inputs that already have a remote input to pair with are about to be committed, so they don't count against the capacity.
     pub fn can_add_local_input(&mut self) -> bool {
-        self.iq.local_queue_length() < MAX_QUEUE_LENGTH
+        let local_queue_length = self.iq.local_queue_length();
+        let committable = std::cmp::min(
+            local_queue_length.saturating_sub(self.local_delay() as usize),
+            self.iq.remote_queue_length(),
+        );
+        local_queue_length - committable < self.max_queue_length
     }
 
     pub fn add_local_input(&mut self, input: input::Input) {
         log::debug!("local input: {:?}", input);
+        self.unacked_local_inputs.push_back(input.clone());
         self.iq.add_local_input(input);
     }
 
-    pub fn can_add_remote_input(&mut self) -> bool {
-        self.iq.remote_queue_length() < MAX_QUEUE_LENGTH
+    // This mirrors can_add_local_input on the remote's side: it only counts inputs it hasn't got one of ours to pair with against the capacity. We've already sent it ours for any remote input that has a local one to pair with here, so those may legitimately go over the capacity, and are committed on our next tick anyway.
+    pub fn check_remote_input_capacity(&self) -> Result<(), InputQueueOverflow> {
+        let remote_queue_length = self.iq.remote_queue_length();
+        let committable = std::cmp::min(remote_queue_length, self.iq.local_queue_length());
+        let uncommittable = remote_queue_length - committable;
+        if uncommittable >= self.max_queue_length {
+            return Err(InputQueueOverflow {
+                uncommittable,
+                max_queue_length: self.max_queue_length,
+            });
+        }
+        Ok(())
     }
 
     pub fn add_remote_input(&mut self, input: input::Input) {
         log::debug!("remote input: {:?}", input);
+        self.last_received_remote_tick = Some(input.local_tick);
+
+        // The remote tick on an incoming input is the last of our inputs the remote committed. The channel is ordered, so everything before it has certainly arrived too.
+        while self
+            .unacked_local_inputs
+            .front()
+            .map_or(false, |unacked| unacked.local_tick < input.remote_tick)
+        {
+            self.unacked_local_inputs.pop_front();
+        }
+
         self.iq.add_remote_input(input);
     }
 
+    pub fn last_received_remote_tick(&self) -> Option<u32> {
+        self.last_received_remote_tick
+    }
+
+    pub fn unacked_local_inputs_after(&self, tick: Option<u32>) -> Vec<input::Input> {
+        self.unacked_local_inputs
+            .iter()
+            .filter(|input| tick.map_or(true, |tick| input.local_tick > tick))
+            .cloned()
+            .collect()
+    }
+
     pub fn add_local_pending_turn(&mut self, marshaled: Vec<u8>) {
         self.local_pending_turn = Some(LocalPendingTurn {
             ticks_left: 64,
@@ -564,6 +1561,22 @@ impl Round {
         }
     }
 
+    pub fn record_telemetry(&mut self, tick: u32, resimulated_ticks: u32) {
+        let telemetry = replay::Telemetry {
+            tick,
+            local_queue_length: self.local_queue_length() as u32,
+            remote_queue_length: self.remote_queue_length() as u32,
+            tps_adjustment: self.tps_adjustment(),
+            resimulated_ticks,
+            remote_lag: self.last_committed_remote_input.lag(),
+        };
+        self.replay_writer
+            .as_mut()
+            .unwrap()
+            .offer_telemetry(&telemetry)
+            .expect("write telemetry");
+    }
+
     pub fn tps_adjustment(&self) -> i32 {
         let last_local_input = match &self.last_input {
             Some(input::Pair { local, .. }) => local,
@@ -573,6 +1586,17 @@ impl Round {
         };
         (last_local_input.lag() - self.last_committed_remote_input.lag())
             - (self.local_delay() as i32 - self.remote_delay() as i32)
+            - self.backpressure()
+    }
+
+    // Once our queue is more than half full, we slow down progressively, down to half speed when it's full.
+    fn backpressure(&self) -> i32 {
+        let half = self.max_queue_length / 2;
+        let local_queue_length = std::cmp::min(self.local_queue_length(), self.max_queue_length);
+        if local_queue_length <= half {
+            return 0;
+        }
+        ((local_queue_length - half) * game::EXPECTED_FPS as usize / self.max_queue_length) as i32
     }
 }
 
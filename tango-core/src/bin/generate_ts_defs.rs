@@ -10,6 +10,9 @@ fn main() -> anyhow::Result<()> {
         &[
             &tango_core::ipc::Args::INFO,
             &tango_core::ipc::Notification::INFO,
+            &tango_core::ipc::Request::INFO,
+            &tango_core::ipc::NegotiationProgress::INFO,
+            &tango_core::ipc::NegotiationFailure::INFO,
         ],
     )?;
     Ok(())
@@ -9,6 +9,9 @@ struct Cli {
     #[clap(parse(from_os_str))]
     path: std::path::PathBuf,
 
+    #[clap(long, default_value = "1")]
+    round: usize,
+
     #[clap(subcommand)]
     action: Action,
 }
@@ -41,10 +44,59 @@ struct DumpVideoCli {
 #[derive(clap::Parser)]
 struct DumpEWRAMCli {}
 
+#[derive(clap::Parser)]
+struct RepairCli {
+    #[clap(parse(from_os_str))]
+    output_path: std::path::PathBuf,
+}
+
+#[derive(clap::Parser)]
+struct VerifyPerspectivesCli {
+    #[clap(parse(from_os_str))]
+    rom_path: std::path::PathBuf,
+}
+
+#[derive(clap::Parser)]
+struct VerifyCli {
+    #[clap(parse(from_os_str))]
+    rom_path: std::path::PathBuf,
+}
+
+#[derive(clap::Parser)]
+struct InfoCli {}
+
+#[derive(clap::Parser)]
+struct VerifySignatureCli {
+    #[clap(long)]
+    public_key: Option<String>,
+}
+
+#[derive(clap::ArgEnum, Clone)]
+enum InputsFormat {
+    Jsonl,
+    Csv,
+}
+
+#[derive(clap::Parser)]
+struct DumpInputsCli {
+    #[clap(arg_enum, short('f'), long, default_value = "jsonl")]
+    format: InputsFormat,
+}
+
+#[derive(clap::Parser)]
+struct DumpTelemetryCli {}
+
 #[derive(clap::Subcommand)]
 enum Action {
     DumpVideo(DumpVideoCli),
     DumpEWRAM(DumpEWRAMCli),
+    DumpInputs(DumpInputsCli),
+    DumpTelemetry(DumpTelemetryCli),
+    Repair(RepairCli),
+    VerifyPerspectives(VerifyPerspectivesCli),
+    Verify(VerifyCli),
+    Info(InfoCli),
+    VerifySignature(VerifySignatureCli),
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -58,7 +110,25 @@ fn main() -> Result<(), anyhow::Error> {
 
     let mut f = std::fs::File::open(&args.path)?;
 
-    let replay = tango_core::replay::Replay::decode(&mut f)?;
+    // Repairing rewrites every round of the match, so it doesn't pick one out.
+    if let Action::Repair(args) = args.action {
+        return repair(args, tango_core::replay::MatchReplay::recover(&mut f)?);
+    }
+
+    let match_replay = tango_core::replay::MatchReplay::decode(&mut f)?;
+    let num_rounds = match_replay.rounds.len();
+    let replay = match_replay
+        .rounds
+        .into_iter()
+        .nth(args.round.wrapping_sub(1))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "round {} does not exist, replay has {} round(s)",
+                args.round,
+                num_rounds
+            )
+        })?
+        .replay;
 
     log::info!(
         "replay is for {} (crc32 = {:08x})",
@@ -69,44 +139,45 @@ fn main() -> Result<(), anyhow::Error> {
     match args.action {
         Action::DumpVideo(args) => dump_video(args, replay),
         Action::DumpEWRAM(args) => dump_ewram(args, replay),
+        Action::DumpInputs(args) => dump_inputs(args, replay),
+        Action::DumpTelemetry(args) => dump_telemetry(args, replay),
+        Action::Repair(_) => unreachable!(),
+        Action::VerifyPerspectives(args) => verify_perspectives(args, replay),
+        Action::Verify(args) => verify(args, replay),
+        Action::Info(args) => info(args, replay),
+        Action::VerifySignature(args) => verify_signature(args, replay),
     }
 }
 
 fn dump_video(args: DumpVideoCli, replay: tango_core::replay::Replay) -> Result<(), anyhow::Error> {
-    let mut core = mgba::core::Core::new_gba("tango_core")?;
-    core.enable_video_buffer();
-
-    let vf = mgba::vfile::VFile::open(&args.rom_path, mgba::vfile::flags::O_RDONLY)?;
-    core.as_mut().load_rom(vf)?;
-
-    core.as_mut().reset();
-
-    let done = std::sync::Arc::new(parking_lot::Mutex::new(false));
-
-    let input_pairs = replay.input_pairs.clone();
+    let mut player = tango_core::replay::Player::new(&args.rom_path, &replay)?;
 
-    let ff_state = {
-        let done = done.clone();
-        tango_core::fastforwarder::State::new(
-            replay.local_player_index,
-            input_pairs,
-            0,
-            0,
-            Box::new(move || {
-                *done.lock() = true;
-            }),
-        )
-    };
-    let hooks = tango_core::hooks::HOOKS
-        .get(&core.as_ref().game_title())
-        .unwrap();
-    hooks.prepare_for_fastforward(core.as_mut());
+    let vbuf = std::sync::Arc::new(parking_lot::Mutex::new(vec![
+        0u8;
+        (mgba::gba::SCREEN_WIDTH * mgba::gba::SCREEN_HEIGHT * 4)
+            as usize
+    ]));
     {
-        let ff_state = ff_state.clone();
-        core.set_traps(hooks.fastforwarder_traps(ff_state));
+        let vbuf = vbuf.clone();
+        player.set_frame_callback(move |video_buffer| {
+            let mut vbuf = vbuf.lock();
+            vbuf.copy_from_slice(video_buffer);
+            for i in (0..vbuf.len()).step_by(4) {
+                vbuf[i + 3] = 0xff;
+            }
+        });
     }
 
-    core.as_mut().load_state(&replay.local_state)?;
+    const SAMPLE_RATE: f64 = 48000.0;
+    let audio_bytes = std::sync::Arc::new(parking_lot::Mutex::new(vec![]));
+    {
+        let audio_bytes = audio_bytes.clone();
+        player.set_audio_callback(SAMPLE_RATE, move |samples| {
+            let mut audio_bytes = audio_bytes.lock();
+            audio_bytes.resize(samples.len() * 2, 0);
+            LittleEndian::write_i16_into(samples, &mut audio_bytes[..]);
+        });
+    }
 
     let video_output = tempfile::NamedTempFile::new()?;
     let mut video_child = std::process::Command::new(&args.ffmpeg)
@@ -143,47 +214,25 @@ fn dump_video(args: DumpVideoCli, replay: tango_core::replay::Replay) -> Result<
         .arg(&audio_output.path())
         .spawn()?;
 
-    const SAMPLE_RATE: f64 = 48000.0;
-    let mut samples = vec![0i16; SAMPLE_RATE as usize];
-    let mut vbuf = vec![0u8; (mgba::gba::SCREEN_WIDTH * mgba::gba::SCREEN_HEIGHT * 4) as usize];
-    write!(std::io::stdout(), "{}\n", ff_state.inputs_pairs_left())?;
-    while !*done.lock() {
-        core.as_mut().run_frame();
-        let clock_rate = core.as_ref().frequency();
-        let n = {
-            let mut core = core.as_mut();
-            let mut left = core.audio_channel(0);
-            left.set_rates(clock_rate as f64, SAMPLE_RATE);
-            let n = left.samples_avail();
-            left.read_samples(&mut samples[..(n * 2) as usize], left.samples_avail(), true);
-            n
-        };
-        {
-            let mut core = core.as_mut();
-            let mut right = core.audio_channel(1);
-            right.set_rates(clock_rate as f64, SAMPLE_RATE);
-            right.read_samples(&mut samples[1..(n * 2) as usize], n, true);
-        }
-        let samples = &samples[..(n * 2) as usize];
+    write!(std::io::stdout(), "{}\n", player.inputs_pairs_left())?;
+    while !player.is_done() {
+        player.step()?;
 
-        vbuf.copy_from_slice(core.video_buffer().unwrap());
-        for i in (0..vbuf.len()).step_by(4) {
-            vbuf[i + 3] = 0xff;
-        }
         video_child
             .stdin
             .as_mut()
             .unwrap()
-            .write_all(vbuf.as_slice())?;
+            .write_all(vbuf.lock().as_slice())?;
 
-        let mut audio_bytes = vec![0u8; samples.len() * 2];
-        LittleEndian::write_i16_into(&samples, &mut audio_bytes[..]);
         audio_child
             .stdin
             .as_mut()
             .unwrap()
-            .write_all(&audio_bytes)?;
-        write!(std::io::stdout(), "{}\n", ff_state.inputs_pairs_left())?;
+            .write_all(audio_bytes.lock().as_slice())?;
+        write!(std::io::stdout(), "{}\n", player.inputs_pairs_left())?;
+    }
+    if !player.has_battle_ended() {
+        log::warn!("replay ran out of inputs before the battle ended, the video will stop short");
     }
 
     video_child.stdin = None;
@@ -214,3 +263,292 @@ fn dump_ewram(
     std::io::stdout().flush()?;
     Ok(())
 }
+
+const BUTTON_NAMES: &[(u32, &str)] = &[
+    (mgba::input::keys::A, "A"),
+    (mgba::input::keys::B, "B"),
+    (mgba::input::keys::SELECT, "SELECT"),
+    (mgba::input::keys::START, "START"),
+    (mgba::input::keys::RIGHT, "RIGHT"),
+    (mgba::input::keys::LEFT, "LEFT"),
+    (mgba::input::keys::UP, "UP"),
+    (mgba::input::keys::DOWN, "DOWN"),
+    (mgba::input::keys::R, "R"),
+    (mgba::input::keys::L, "L"),
+];
+
+fn button_names(joyflags: u16) -> Vec<&'static str> {
+    BUTTON_NAMES
+        .iter()
+        .filter(|(key, _)| joyflags as u32 & key != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
+#[derive(serde::Serialize)]
+struct InputRecord {
+    joyflags: u16,
+    buttons: Vec<&'static str>,
+    custom_screen_state: u8,
+    turn_len: usize,
+}
+
+impl InputRecord {
+    fn new(input: &tango_core::input::Input) -> Self {
+        Self {
+            joyflags: input.joyflags,
+            buttons: button_names(input.joyflags),
+            custom_screen_state: input.custom_screen_state,
+            turn_len: input.turn.len(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct InputPairRecord {
+    local_tick: u32,
+    remote_tick: u32,
+    local: InputRecord,
+    remote: InputRecord,
+}
+
+fn dump_inputs(
+    args: DumpInputsCli,
+    replay: tango_core::replay::Replay,
+) -> Result<(), anyhow::Error> {
+    let mut stdout = std::io::BufWriter::new(std::io::stdout());
+
+    if let InputsFormat::Csv = args.format {
+        writeln!(
+            stdout,
+            "local_tick,remote_tick,local_joyflags,local_buttons,local_custom_screen_state,local_turn_len,remote_joyflags,remote_buttons,remote_custom_screen_state,remote_turn_len"
+        )?;
+    }
+
+    for ip in &replay.input_pairs {
+        let record = InputPairRecord {
+            local_tick: ip.local.local_tick,
+            remote_tick: ip.local.remote_tick,
+            local: InputRecord::new(&ip.local),
+            remote: InputRecord::new(&ip.remote),
+        };
+
+        match args.format {
+            InputsFormat::Jsonl => {
+                serde_json::to_writer(&mut stdout, &record)?;
+                writeln!(stdout)?;
+            }
+            InputsFormat::Csv => {
+                writeln!(
+                    stdout,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    record.local_tick,
+                    record.remote_tick,
+                    record.local.joyflags,
+                    record.local.buttons.join("+"),
+                    record.local.custom_screen_state,
+                    record.local.turn_len,
+                    record.remote.joyflags,
+                    record.remote.buttons.join("+"),
+                    record.remote.custom_screen_state,
+                    record.remote.turn_len,
+                )?;
+            }
+        }
+    }
+
+    stdout.flush()?;
+    Ok(())
+}
+
+fn dump_telemetry(
+    _args: DumpTelemetryCli,
+    replay: tango_core::replay::Replay,
+) -> Result<(), anyhow::Error> {
+    if replay.telemetry.is_empty() {
+        anyhow::bail!("replay does not contain telemetry");
+    }
+
+    let mut stdout = std::io::BufWriter::new(std::io::stdout());
+    for sample in &replay.telemetry {
+        serde_json::to_writer(&mut stdout, sample)?;
+        writeln!(stdout)?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn repair(
+    args: RepairCli,
+    match_replay: tango_core::replay::MatchReplay,
+) -> Result<(), anyhow::Error> {
+    let f = std::fs::File::create(&args.output_path)?;
+    let mut match_writer =
+        tango_core::replay::MatchWriter::new(Box::new(f), &match_replay.rng_commitment)?;
+    let num_rounds = match_replay.rounds.len();
+    for (i, round) in match_replay.rounds.into_iter().enumerate() {
+        let writer = tango_core::replay::Writer::new(
+            match_writer.begin_round()?,
+            round.replay.local_player_index,
+            0,
+            0,
+            false,
+            None,
+        )?;
+        if round.replay.signature.is_some() {
+            log::warn!(
+                "repaired replays cannot be re-signed, the signature for round {} will be dropped",
+                i + 1
+            );
+        }
+        match_writer.end_round(repair_round(writer, &round.replay)?, round.winner)?;
+        log::info!(
+            "repaired round {} with {} inputs",
+            i + 1,
+            round.replay.input_pairs.len()
+        );
+    }
+
+    log::info!(
+        "repaired replay with {} round(s) written to {}",
+        num_rounds,
+        args.output_path.display()
+    );
+    Ok(())
+}
+
+fn repair_round(
+    mut writer: tango_core::replay::Writer,
+    replay: &tango_core::replay::Replay,
+) -> Result<Box<dyn tango_core::replay::WriteSeek + Send>, anyhow::Error> {
+    writer.write_state(&replay.local_state)?;
+    writer.write_state_placeholder()?;
+    writer.write_metadata(&replay.metadata)?;
+    if let Some(remote_state) = &replay.remote_state {
+        writer.write_remote_state(remote_state)?;
+    }
+
+    let mut keyframes = replay.keyframes.iter().peekable();
+    let mut state_hashes = replay.state_hashes.iter().peekable();
+    let mut telemetry = replay.telemetry.iter().peekable();
+    for (i, ip) in replay.input_pairs.iter().enumerate() {
+        while let Some(kf) = keyframes.next_if(|kf| kf.input_index as usize <= i) {
+            writer.write_keyframe(&kf.state)?;
+        }
+        while let Some(sh) = state_hashes.next_if(|sh| sh.input_index as usize <= i) {
+            writer.write_state_hash(sh.hash)?;
+        }
+        while let Some(t) = telemetry.next_if(|t| t.input_index as usize <= i) {
+            writer.write_telemetry(&t.telemetry)?;
+        }
+        writer.write_input(replay.local_player_index, ip)?;
+    }
+    for kf in keyframes.filter(|kf| kf.input_index as usize == replay.input_pairs.len()) {
+        writer.write_keyframe(&kf.state)?;
+    }
+    for sh in state_hashes.filter(|sh| sh.input_index as usize == replay.input_pairs.len()) {
+        writer.write_state_hash(sh.hash)?;
+    }
+    for t in telemetry.filter(|t| t.input_index as usize == replay.input_pairs.len()) {
+        writer.write_telemetry(&t.telemetry)?;
+    }
+    // These are only ever written once the round is over, so they always go at the end.
+    if let Some(forfeit) = &replay.forfeit {
+        writer.write_forfeit(forfeit.player_index)?;
+    }
+    for goodbye in &replay.goodbyes {
+        writer.write_goodbye(goodbye.player_index, &goodbye.reason)?;
+    }
+    Ok(writer.finish()?)
+}
+
+fn verify_perspectives(
+    args: VerifyPerspectivesCli,
+    replay: tango_core::replay::Replay,
+) -> Result<(), anyhow::Error> {
+    match replay.verify_perspectives(&args.rom_path)? {
+        Some(divergence) => {
+            anyhow::bail!(
+                "perspectives diverged at tick {}: {}",
+                divergence.tick,
+                divergence.reason
+            );
+        }
+        None => {
+            log::info!("both perspectives agree");
+        }
+    }
+    Ok(())
+}
+
+fn verify(args: VerifyCli, replay: tango_core::replay::Replay) -> Result<(), anyhow::Error> {
+    if replay.state_hashes.is_empty() {
+        anyhow::bail!("replay does not contain any state hashes");
+    }
+
+    let core = {
+        let mut core = mgba::core::Core::new_gba("tango_core")?;
+        let vf = mgba::vfile::VFile::open(&args.rom_path, mgba::vfile::flags::O_RDONLY)?;
+        core.as_mut().load_rom(vf)?;
+        core
+    };
+    let hooks = tango_core::hooks::HOOKS
+        .get(&core.as_ref().game_title())
+        .unwrap();
+
+    let mut ff = tango_core::fastforwarder::Fastforwarder::new(
+        &args.rom_path,
+        hooks,
+        replay.local_player_index,
+        Box::new(tango_core::input::ABInputPredictor),
+    )?;
+
+    match replay.verify_state_hashes(&mut ff)? {
+        Some(divergence) => {
+            anyhow::bail!(
+                "replay diverged at tick {}: {}",
+                divergence.tick,
+                divergence.reason
+            );
+        }
+        None => {
+            log::info!("all {} state hashes match", replay.state_hashes.len());
+        }
+    }
+    Ok(())
+}
+
+fn info(_args: InfoCli, replay: tango_core::replay::Replay) -> Result<(), anyhow::Error> {
+    serde_json::to_writer_pretty(std::io::stdout(), &replay.metadata)?;
+    writeln!(std::io::stdout())?;
+    if let Some(forfeit) = &replay.forfeit {
+        log::info!(
+            "player {} forfeited after {} inputs",
+            forfeit.player_index,
+            forfeit.input_index
+        );
+    }
+    for goodbye in &replay.goodbyes {
+        log::info!(
+            "player {} left after {} inputs: {:?}",
+            goodbye.player_index,
+            goodbye.input_index,
+            goodbye.reason
+        );
+    }
+    Ok(())
+}
+
+fn verify_signature(
+    args: VerifySignatureCli,
+    replay: tango_core::replay::Replay,
+) -> Result<(), anyhow::Error> {
+    let public_key = hex::encode(replay.verify_signature()?.as_bytes());
+    if let Some(expected) = &args.public_key {
+        if !expected.eq_ignore_ascii_case(&public_key) {
+            anyhow::bail!("replay was signed by {}, expected {}", public_key, expected);
+        }
+    }
+    log::info!("signature ok, signed by {}", public_key);
+    Ok(())
+}
@@ -8,6 +8,9 @@ struct Cli {
     #[clap(long)]
     remote: bool,
 
+    #[clap(long, default_value = "0")]
+    start_tick: u32,
+
     #[clap(parse(from_os_str))]
     rom_path: std::path::PathBuf,
 
@@ -15,6 +18,24 @@ struct Cli {
     path: std::path::PathBuf,
 }
 
+// Samples are produced a frame at a time by the player thread and drained by the audio device.
+struct QueueStream(std::sync::Arc<parking_lot::Mutex<std::collections::VecDeque<i16>>>);
+
+impl tango_core::audio::Stream for QueueStream {
+    fn fill(&mut self, buf: &mut [i16]) -> usize {
+        let mut queue = self.0.lock();
+        let n = std::cmp::min(buf.len(), queue.len()) / 2 * 2;
+        for (x, y) in buf.iter_mut().zip(queue.drain(..n)) {
+            *x = y;
+        }
+        n
+    }
+}
+
+// The GBA runs at 16777216 Hz with 280896 cycles per frame.
+const FRAME_DURATION: std::time::Duration =
+    std::time::Duration::from_nanos(280896 * 1_000_000_000 / 16777216);
+
 fn main() -> Result<(), anyhow::Error> {
     env_logger::Builder::from_default_env()
         .filter(Some("tango_core"), log::LevelFilter::Info)
@@ -24,23 +45,35 @@ fn main() -> Result<(), anyhow::Error> {
 
     let args = Cli::parse();
 
-    let mut f = std::fs::File::open(args.path)?;
-
-    let replay = tango_core::replay::Replay::decode(&mut f)?;
+    if args.remote && args.start_tick != 0 {
+        anyhow::bail!("seeking is not supported from the remote perspective");
+    }
 
+    let mut f = std::fs::File::open(&args.path)?;
+
+    let match_replay = tango_core::replay::MatchReplay::decode(&mut f)?;
+    let mut rounds = match_replay.rounds.into_iter();
+    let replay = rounds
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("replay has no rounds"))?
+        .replay;
+
+    // The two sides may be playing different, compatible games.
+    let state = if args.remote {
+        replay
+            .remote_state
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("replay does not contain the remote player's state"))?
+    } else {
+        &replay.local_state
+    };
     log::info!(
-        "replay is for {} (crc32 = {:08x})",
-        replay.local_state.rom_title(),
-        replay.local_state.rom_crc32()
+        "replay is for {} (crc32 = {:08x}), {} round(s) left after this one",
+        state.rom_title(),
+        state.rom_crc32(),
+        rounds.len()
     );
 
-    let mut core = mgba::core::Core::new_gba("tango_core")?;
-
-    let vf = mgba::vfile::VFile::open(&args.rom_path, mgba::vfile::flags::O_RDONLY)?;
-    core.as_mut().load_rom(vf)?;
-
-    core.enable_video_buffer();
-
     let vbuf = std::sync::Arc::new(parking_lot::Mutex::new(vec![
         0u8;
         (mgba::gba::SCREEN_WIDTH * mgba::gba::SCREEN_HEIGHT * 4)
@@ -54,6 +87,89 @@ fn main() -> Result<(), anyhow::Error> {
     let supported_config = tango_core::audio::get_supported_config(&audio_device)?;
     log::info!("selected audio config: {:?}", supported_config);
 
+    let audio_queue =
+        std::sync::Arc::new(parking_lot::Mutex::new(std::collections::VecDeque::new()));
+    let stream = tango_core::audio::open_stream(
+        &audio_device,
+        &supported_config,
+        QueueStream(audio_queue.clone()),
+    )?;
+    stream.play()?;
+
+    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+    // Player holds the core, which can't be sent across threads, so each one is created on the thread that runs it.
+    let _player_thread = {
+        let vbuf = vbuf.clone();
+        let done = done.clone();
+        let rom_path = args.rom_path.clone();
+        let remote = args.remote;
+        let start_tick = args.start_tick;
+        let sample_rate = supported_config.sample_rate().0 as f64;
+        std::thread::spawn(move || {
+            let mut next = Some((replay, start_tick));
+            while let Some((replay, start_tick)) = next.take() {
+                let player = if start_tick != 0 {
+                    tango_core::replay::Player::new_at_tick(&rom_path, &replay, start_tick)
+                } else if remote {
+                    tango_core::replay::Player::new_remote(&rom_path, &replay)
+                } else {
+                    tango_core::replay::Player::new(&rom_path, &replay)
+                };
+                let mut player = match player {
+                    Ok(player) => player,
+                    Err(e) => {
+                        log::error!("failed to start playback: {}", e);
+                        break;
+                    }
+                };
+
+                {
+                    let vbuf = vbuf.clone();
+                    player.set_frame_callback(move |video_buffer| {
+                        let mut vbuf = vbuf.lock();
+                        vbuf.copy_from_slice(video_buffer);
+                        for i in (0..vbuf.len()).step_by(4) {
+                            vbuf[i + 3] = 0xff;
+                        }
+                    });
+                }
+                {
+                    let audio_queue = audio_queue.clone();
+                    player.set_audio_callback(sample_rate, move |samples| {
+                        audio_queue.lock().extend(samples);
+                    });
+                }
+
+                let mut deadline = std::time::Instant::now();
+                while !player.is_done() {
+                    if let Err(e) = player.step() {
+                        log::error!("playback failed: {}", e);
+                        done.store(true, std::sync::atomic::Ordering::Relaxed);
+                        return;
+                    }
+
+                    deadline += FRAME_DURATION;
+                    let now = std::time::Instant::now();
+                    if deadline > now {
+                        std::thread::sleep(deadline - now);
+                    } else {
+                        deadline = now;
+                    }
+                }
+                if !player.has_battle_ended() {
+                    log::warn!("round ran out of inputs before the battle ended");
+                }
+
+                next = rounds.next().map(|round| {
+                    log::info!("round ended, {} round(s) left", rounds.len());
+                    (round.replay, 0)
+                });
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        })
+    };
+
     let event_loop = winit::event_loop::EventLoop::new();
 
     let window = {
@@ -78,108 +194,35 @@ fn main() -> Result<(), anyhow::Error> {
         .build()?
     };
 
-    let done = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    let hooks = tango_core::hooks::HOOKS
-        .get(&core.as_ref().game_title())
-        .unwrap();
-    hooks.prepare_for_fastforward(core.as_mut());
-
-    let local_player_index = if !args.remote {
-        replay.local_player_index
-    } else {
-        1 - replay.local_player_index
-    };
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = winit::event_loop::ControlFlow::Poll;
 
-    let mut input_pairs = replay.input_pairs.clone();
-    if args.remote {
-        for pair in input_pairs.iter_mut() {
-            std::mem::swap(&mut pair.local, &mut pair.remote);
+        if done.load(std::sync::atomic::Ordering::Relaxed) {
+            *control_flow = winit::event_loop::ControlFlow::Exit;
+            return;
         }
-    }
-
-    {
-        let done = done.clone();
-        core.set_traps(
-            hooks.fastforwarder_traps(tango_core::fastforwarder::State::new(
-                local_player_index,
-                input_pairs,
-                0,
-                0,
-                Box::new(move || {
-                    done.store(true, std::sync::atomic::Ordering::Relaxed);
-                }),
-            )),
-        );
-    }
-
-    let thread = mgba::thread::Thread::new(core);
-    thread.start().expect("start thread");
-    thread.handle().pause();
-    thread.handle().run_on_core(|mut core| {
-        core.gba_mut()
-            .sync_mut()
-            .as_mut()
-            .expect("sync")
-            .set_fps_target(60.0);
-    });
-    {
-        let vbuf = vbuf.clone();
-        thread.set_frame_callback(move |_core, video_buffer| {
-            let mut vbuf = vbuf.lock();
-            vbuf.copy_from_slice(video_buffer);
-            for i in (0..vbuf.len()).step_by(4) {
-                vbuf[i + 3] = 0xff;
-            }
-        });
-    }
-
-    let stream = tango_core::audio::open_stream(
-        &audio_device,
-        &supported_config,
-        tango_core::audio::mgba_stream::MGBAStream::new(
-            thread.handle(),
-            supported_config.sample_rate(),
-        ),
-    )?;
-    stream.play()?;
-
-    thread.handle().run_on_core(move |mut core| {
-        core.load_state(&replay.local_state).expect("load state");
-    });
-    thread.handle().unpause();
-
-    {
-        let vbuf = vbuf.clone();
-        event_loop.run(move |event, _, control_flow| {
-            *control_flow = winit::event_loop::ControlFlow::Poll;
 
-            if done.load(std::sync::atomic::Ordering::Relaxed) {
-                *control_flow = winit::event_loop::ControlFlow::Exit;
-                return;
+        match event {
+            winit::event::Event::MainEventsCleared => {
+                let vbuf = vbuf.lock().clone();
+                pixels.get_frame().copy_from_slice(&vbuf);
+                pixels.render().expect("render pixels");
             }
-
-            match event {
-                winit::event::Event::MainEventsCleared => {
-                    let vbuf = vbuf.lock().clone();
-                    pixels.get_frame().copy_from_slice(&vbuf);
-                    pixels.render().expect("render pixels");
-                }
-                winit::event::Event::WindowEvent {
-                    event: ref window_event,
-                    ..
-                } => {
-                    match window_event {
-                        winit::event::WindowEvent::CloseRequested => {
-                            *control_flow = winit::event_loop::ControlFlow::Exit;
-                        }
-                        winit::event::WindowEvent::Resized(size) => {
-                            pixels.resize_surface(size.width, size.height);
-                        }
-                        _ => {}
-                    };
-                }
-                _ => {}
+            winit::event::Event::WindowEvent {
+                event: ref window_event,
+                ..
+            } => {
+                match window_event {
+                    winit::event::WindowEvent::CloseRequested => {
+                        *control_flow = winit::event_loop::ControlFlow::Exit;
+                    }
+                    winit::event::WindowEvent::Resized(size) => {
+                        pixels.resize_surface(size.width, size.height);
+                    }
+                    _ => {}
+                };
             }
-        });
-    }
+            _ => {}
+        }
+    });
 }
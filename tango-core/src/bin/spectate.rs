@@ -0,0 +1,228 @@
+#![windows_subsystem = "windows"]
+
+use clap::Parser;
+use cpal::traits::{HostTrait, StreamTrait};
+
+#[derive(clap::Parser)]
+struct Cli {
+    #[clap(long, default_value = "180")]
+    delay: usize,
+
+    #[clap(parse(from_os_str))]
+    rom_path: std::path::PathBuf,
+
+    addr: String,
+
+    token: String,
+}
+
+// If we get this close to running out of inputs, we pause and wait for the buffer to fill back up to the full delay.
+const MIN_BUFFERED_INPUTS: usize = 10;
+
+fn main() -> Result<(), anyhow::Error> {
+    env_logger::Builder::from_default_env()
+        .filter(Some("tango_core"), log::LevelFilter::Info)
+        .filter(Some("spectate"), log::LevelFilter::Info)
+        .init();
+    mgba::log::init();
+
+    let args = Cli::parse();
+
+    let stream = std::net::TcpStream::connect(&args.addr)?;
+    log::info!("connected to {}", args.addr);
+    tango_core::spectator::write_token(&stream, &args.token)?;
+
+    let (event_tx, event_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        let mut receiver = tango_core::spectator::Receiver::new(std::io::BufReader::new(stream));
+        loop {
+            match receiver.next_event() {
+                Ok(Some(event)) => {
+                    if event_tx.send(event).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    log::info!("match ended");
+                    break;
+                }
+                Err(e) => {
+                    log::error!("failed to read from spectator stream: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let mut core = mgba::core::Core::new_gba("tango_core")?;
+
+    let vf = mgba::vfile::VFile::open(&args.rom_path, mgba::vfile::flags::O_RDONLY)?;
+    core.as_mut().load_rom(vf)?;
+
+    core.enable_video_buffer();
+
+    let vbuf = std::sync::Arc::new(parking_lot::Mutex::new(vec![
+        0u8;
+        (mgba::gba::SCREEN_WIDTH * mgba::gba::SCREEN_HEIGHT * 4)
+            as usize
+    ]));
+
+    let audio_device = cpal::default_host()
+        .default_output_device()
+        .ok_or_else(|| anyhow::format_err!("could not open audio device"))?;
+
+    let supported_config = tango_core::audio::get_supported_config(&audio_device)?;
+    log::info!("selected audio config: {:?}", supported_config);
+
+    let event_loop = winit::event_loop::EventLoop::new();
+
+    let window = {
+        let size =
+            winit::dpi::LogicalSize::new(mgba::gba::SCREEN_WIDTH * 3, mgba::gba::SCREEN_HEIGHT * 3);
+        winit::window::WindowBuilder::new()
+            .with_title("tango_core spectate")
+            .with_inner_size(size)
+            .with_min_inner_size(size)
+            .build(&event_loop)?
+    };
+
+    let mut pixels = {
+        let window_size = window.inner_size();
+        let surface_texture =
+            pixels::SurfaceTexture::new(window_size.width, window_size.height, &window);
+        pixels::PixelsBuilder::new(
+            mgba::gba::SCREEN_WIDTH,
+            mgba::gba::SCREEN_HEIGHT,
+            surface_texture,
+        )
+        .build()?
+    };
+
+    let hooks = tango_core::hooks::HOOKS
+        .get(&core.as_ref().game_title())
+        .ok_or_else(|| anyhow::anyhow!("unsupported game"))?;
+    hooks.prepare_for_fastforward(core.as_mut());
+
+    // Rounds swap their inputs into this state as they start.
+    let ff_state = tango_core::fastforwarder::State::new(0, vec![], 0, 0, Box::new(|| {}));
+    core.set_traps(hooks.fastforwarder_traps(ff_state.clone()));
+
+    let thread = mgba::thread::Thread::new(core);
+    thread.start().expect("start thread");
+    thread.handle().pause();
+    thread.handle().run_on_core(|mut core| {
+        core.gba_mut()
+            .sync_mut()
+            .as_mut()
+            .expect("sync")
+            .set_fps_target(60.0);
+    });
+    {
+        let vbuf = vbuf.clone();
+        thread.set_frame_callback(move |_core, video_buffer| {
+            let mut vbuf = vbuf.lock();
+            vbuf.copy_from_slice(video_buffer);
+            for i in (0..vbuf.len()).step_by(4) {
+                vbuf[i + 3] = 0xff;
+            }
+        });
+    }
+
+    let stream = tango_core::audio::open_stream(
+        &audio_device,
+        &supported_config,
+        tango_core::audio::mgba_stream::MGBAStream::new(
+            thread.handle(),
+            supported_config.sample_rate(),
+        ),
+    )?;
+    stream.play()?;
+
+    {
+        let vbuf = vbuf.clone();
+        let thread_handle = thread.handle();
+        let delay = args.delay;
+        let mut in_round = false;
+        let mut paused = true;
+        event_loop.run(move |event, _, control_flow| {
+            *control_flow = winit::event_loop::ControlFlow::Poll;
+
+            for spectator_event in event_rx.try_iter() {
+                match spectator_event {
+                    tango_core::spectator::Event::RoundStarted {
+                        local_player_index,
+                        state,
+                    } => {
+                        log::info!("round started");
+                        in_round = true;
+                        if !paused {
+                            thread_handle.pause();
+                            paused = true;
+                        }
+                        let next_ff_state = tango_core::fastforwarder::State::new(
+                            local_player_index,
+                            vec![],
+                            0,
+                            0,
+                            Box::new(|| {}),
+                        );
+                        // This has to happen right away rather than on the core thread, or inputs that arrive before the core gets to it would go to the previous round.
+                        ff_state.replace_with(&next_ff_state);
+                        thread_handle.run_on_core(move |mut core| {
+                            core.load_state(&state).expect("load state");
+                        });
+                    }
+                    tango_core::spectator::Event::Input(ip) => {
+                        ff_state.push_input_pair(ip);
+                    }
+                    tango_core::spectator::Event::RoundEnded => {
+                        log::info!("round ended");
+                        in_round = false;
+                    }
+                }
+            }
+
+            // Once the round has ended there's nothing more to wait for, so we play out whatever is left.
+            let inputs_left = ff_state.inputs_pairs_left();
+            if in_round {
+                if paused && inputs_left >= delay {
+                    thread_handle.unpause();
+                    paused = false;
+                } else if !paused && inputs_left < MIN_BUFFERED_INPUTS {
+                    log::info!("spectator buffer ran low, waiting for more inputs");
+                    thread_handle.pause();
+                    paused = true;
+                }
+            } else if paused && inputs_left > 0 {
+                thread_handle.unpause();
+                paused = false;
+            } else if !paused && inputs_left == 0 {
+                thread_handle.pause();
+                paused = true;
+            }
+
+            match event {
+                winit::event::Event::MainEventsCleared => {
+                    let vbuf = vbuf.lock().clone();
+                    pixels.get_frame().copy_from_slice(&vbuf);
+                    pixels.render().expect("render pixels");
+                }
+                winit::event::Event::WindowEvent {
+                    event: ref window_event,
+                    ..
+                } => {
+                    match window_event {
+                        winit::event::WindowEvent::CloseRequested => {
+                            *control_flow = winit::event_loop::ControlFlow::Exit;
+                        }
+                        winit::event::WindowEvent::Resized(size) => {
+                            pixels.resize_surface(size.width, size.height);
+                        }
+                        _ => {}
+                    };
+                }
+                _ => {}
+            }
+        });
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{battle, game, input};
+use crate::{battle, game, input, ipc, protocol};
 
 pub struct RoundStateFacadeGuard<'a> {
     guard: tokio::sync::MutexGuard<'a, battle::RoundState>,
@@ -14,8 +14,25 @@ impl<'a> RoundStateFacadeGuard<'a> {
             .add_local_pending_turn(local_turn);
     }
 
-    pub async fn end_round(&mut self) {
-        self.guard.end_round().await.expect("end round");
+    // Returns whether the set has been decided by this round.
+    pub async fn end_round(&mut self) -> bool {
+        let round_ended = match self.guard.end_round().await.expect("end round") {
+            Some(round_ended) => round_ended,
+            None => {
+                return false;
+            }
+        };
+        let decided = self.match_.is_set_decided(&round_ended.score);
+        self.match_.report_round_ended(round_ended).await;
+        decided
+    }
+
+    pub fn can_add_local_input(&mut self) -> bool {
+        self.guard
+            .round
+            .as_mut()
+            .expect("attempted to get round information while no round was active!")
+            .can_add_local_input()
     }
 
     pub fn has_committed_state(&self) -> bool {
@@ -43,12 +60,13 @@ impl<'a> RoundStateFacadeGuard<'a> {
             .as_mut()
             .expect("attempted to get round information while no round was active!");
 
-        let local_tick = current_tick + round.local_delay();
+        round.apply_pending_remote_delay(current_tick);
+
         let remote_tick = round.last_committed_remote_input().local_tick;
 
         // We do it in this order such that:
         // 1. We make sure that the input buffer does not overflow if we were to add an input.
-        // 2. We try to send it to the peer: if it fails, we don't end up desyncing the opponent as we haven't added the input ourselves yet.
+        // 2. We try to send it to the peer: if it fails, the data channel has dropped and the input will be resent from our queue once the session is resumed.
         // 3. We add the input to our buffer: no overflow is guaranteed because we already checked ahead of time.
         //
         // This is all done while the round is locked, so there are no TOCTTOU issues.
@@ -57,31 +75,91 @@ impl<'a> RoundStateFacadeGuard<'a> {
             return false;
         }
 
-        if let Err(e) = self
-            .match_
-            .send_input(
-                round_number,
+        if turn.is_empty() && round.take_local_input_skip() {
+            // Shrinking the delay by one: the input for this tick was already queued at the old delay, so we don't queue another.
+            let local_delay = round.local_delay() - 1;
+            if let Err(e) = self
+                .match_
+                .send_input_delay_changed(round_number, current_tick, local_delay)
+                .await
+            {
+                log::warn!(
+                    "failed to send input delay change, it will be resent on resume: {}",
+                    e
+                );
+            }
+            round.set_local_delay(current_tick, local_delay);
+        } else {
+            if !turn.is_empty() {
+                match round.plan_local_delay_change() {
+                    Some(local_delay) if local_delay > round.local_delay() => {
+                        // Growing the delay by one: the tick we skip over is filled in with a copy of this input, minus the turn.
+                        let filler = input::Input {
+                            local_tick: current_tick + round.local_delay(),
+                            remote_tick,
+                            joyflags,
+                            custom_screen_state,
+                            turn: vec![],
+                        };
+
+                        if let Err(e) = self
+                            .match_
+                            .send_input_delay_changed(round_number, current_tick, local_delay)
+                            .await
+                        {
+                            log::warn!("failed to send input delay change, it will be resent on resume: {}", e);
+                        }
+
+                        if let Err(e) = self
+                            .match_
+                            .send_input(
+                                round_number,
+                                filler.local_tick,
+                                filler.remote_tick,
+                                filler.joyflags,
+                                filler.custom_screen_state,
+                                vec![],
+                            )
+                            .await
+                        {
+                            log::warn!("failed to send input, it will be resent on resume: {}", e);
+                        }
+
+                        round.add_local_input(filler);
+                        round.set_local_delay(current_tick, local_delay);
+                    }
+                    _ => {}
+                }
+            }
+
+            let local_tick = current_tick + round.local_delay();
+
+            if let Err(e) = self
+                .match_
+                .send_input(
+                    round_number,
+                    local_tick,
+                    remote_tick,
+                    joyflags,
+                    custom_screen_state,
+                    turn.clone(),
+                )
+                .await
+            {
+                log::warn!("failed to send input, it will be resent on resume: {}", e);
+            }
+
+            round.add_local_input(input::Input {
                 local_tick,
                 remote_tick,
                 joyflags,
                 custom_screen_state,
-                turn.clone(),
-            )
-            .await
-        {
-            log::warn!("failed to send input: {}", e);
-            return false;
+                turn,
+            });
         }
 
-        round.add_local_input(input::Input {
-            local_tick,
-            remote_tick,
-            joyflags,
-            custom_screen_state,
-            turn,
-        });
-
         let (input_pairs, left) = round.consume_and_peek_local();
+        round.record_rollback(left.len());
 
         let committed_state = round
             .committed_state()
@@ -90,6 +168,7 @@ impl<'a> RoundStateFacadeGuard<'a> {
             .clone();
         let last_committed_remote_input = round.last_committed_remote_input();
 
+        let resimulated_ticks = (input_pairs.len() + left.len()) as u32;
         let (committed_state, dirty_state, last_input) = match round.fastforwarder().fastforward(
             &committed_state,
             &input_pairs,
@@ -117,6 +196,18 @@ impl<'a> RoundStateFacadeGuard<'a> {
         round.set_committed_state(committed_state);
         round.set_last_input(last_input);
 
+        for (tick, hash) in round.take_outgoing_state_hashes() {
+            if let Err(e) = self.match_.send_state_hash(round_number, tick, hash).await {
+                log::warn!("failed to send state hash: {}", e);
+            }
+        }
+
+        if let Some(desync) = round.check_state_hashes() {
+            log::error!("{}", self.match_.report_desync(round_number, desync).await);
+            return false;
+        }
+        round.record_telemetry(current_tick, resimulated_ticks);
+
         core.gba_mut()
             .sync_mut()
             .expect("set fps target")
@@ -126,12 +217,23 @@ impl<'a> RoundStateFacadeGuard<'a> {
     }
 
     pub async fn set_committed_state(&mut self, state: mgba::state::State) {
+        let round_number = self.guard.number;
         let round = self
             .guard
             .round
             .as_mut()
             .expect("attempted to get round information while no round was active!");
-        round.set_committed_state(state);
+        round.set_committed_state(state.clone());
+        round.write_replay_metadata(&self.match_.replay_metadata(
+            round_number,
+            round.local_delay(),
+            round.remote_delay(),
+            &state,
+        ));
+
+        if let Err(e) = self.match_.send_state_committed(round_number, &state).await {
+            log::warn!("failed to send committed state: {}", e);
+        }
     }
 
     pub async fn fill_input_delay(&mut self, current_tick: u32) {
@@ -255,6 +357,11 @@ impl<'a> RoundStateFacadeGuard<'a> {
 
     pub fn set_won_last_round(&mut self, did_win: bool) {
         self.guard.won_last_round = did_win;
+        self.guard
+            .round
+            .as_mut()
+            .expect("attempted to get round information while no round was active!")
+            .set_won(did_win);
     }
 }
 
@@ -275,6 +382,10 @@ impl MatchFacade {
         self.arc.lock_rng().await
     }
 
+    pub async fn wait_for_remote_input(&self) -> bool {
+        self.arc.wait_for_remote_input().await
+    }
+
     pub fn match_type(&self) -> u16 {
         self.arc.match_type()
     }
@@ -293,6 +404,7 @@ struct InnerFacade {
     match_: std::sync::Arc<tokio::sync::Mutex<Option<std::sync::Arc<battle::Match>>>>,
     joyflags: std::sync::Arc<std::sync::atomic::AtomicU32>,
     cancellation_token: tokio_util::sync::CancellationToken,
+    event_loop_proxy: winit::event_loop::EventLoopProxy<game::UserEvent>,
 }
 
 #[derive(Clone)]
@@ -303,11 +415,13 @@ impl Facade {
         match_: std::sync::Arc<tokio::sync::Mutex<Option<std::sync::Arc<battle::Match>>>>,
         joyflags: std::sync::Arc<std::sync::atomic::AtomicU32>,
         cancellation_token: tokio_util::sync::CancellationToken,
+        event_loop_proxy: winit::event_loop::EventLoopProxy<game::UserEvent>,
     ) -> Self {
         Self(std::rc::Rc::new(std::cell::RefCell::new(InnerFacade {
             match_,
             joyflags,
             cancellation_token,
+            event_loop_proxy,
         })))
     }
     pub async fn match_(&self) -> Option<MatchFacade> {
@@ -333,7 +447,35 @@ impl Facade {
     }
 
     pub async fn end_match(&self) {
-        std::process::exit(0);
+        if let Some(match_) = self.match_().await {
+            match_
+                .arc
+                .report_match_ended(ipc::MatchEndReason::Completed)
+                .await;
+            if let Err(e) = match_
+                .arc
+                .shutdown(protocol::GoodbyeReason::MatchEnded)
+                .await
+            {
+                log::warn!("failed to shut down match cleanly: {}", e);
+            } else {
+                match_.arc.wait_for_remote_left().await;
+            }
+        }
+
+        // Dropping the match closes the peer connection.
+        self.abort_match().await;
+
+        if self
+            .0
+            .borrow()
+            .event_loop_proxy
+            .send_event(game::UserEvent::Shutdown)
+            .is_err()
+        {
+            log::warn!("event loop is already gone, exiting immediately");
+            std::process::exit(0);
+        }
     }
 }
 
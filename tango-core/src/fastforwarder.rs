@@ -1,5 +1,6 @@
 use crate::hooks;
 use crate::input;
+use crate::replay;
 
 struct InnerState {
     local_player_index: u8,
@@ -8,6 +9,9 @@ struct InnerState {
     committed_state: Option<mgba::state::State>,
     dirty_time: u32,
     dirty_state: Option<mgba::state::State>,
+    state_hash_interval: Option<u32>,
+    last_state_hash_tick: u32,
+    state_hashes: Vec<(u32, u32, mgba::state::State)>,
     on_battle_ended: Box<dyn Fn() + Send>,
     result: anyhow::Result<()>,
 }
@@ -27,6 +31,9 @@ impl InnerState {
             committed_state: None,
             dirty_time,
             dirty_state: None,
+            state_hash_interval: None,
+            last_state_hash_tick: 0,
+            state_hashes: vec![],
             on_battle_ended,
             result: Ok(()),
         }
@@ -38,6 +45,11 @@ pub struct Fastforwarder {
     state: State,
     hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
     local_player_index: u8,
+    input_predictor: Box<dyn input::InputPredictor + Send>,
+    predictions: usize,
+    mispredictions: usize,
+    state_hash_interval: Option<u32>,
+    state_hashes: Vec<(u32, u32, mgba::state::State)>,
 }
 
 #[derive(Clone)]
@@ -62,6 +74,11 @@ impl State {
         ))))
     }
 
+    pub fn replace_with(&self, other: &State) {
+        let inner = other.0.lock().take();
+        *self.0.lock() = inner;
+    }
+
     pub fn commit_time(&self) -> u32 {
         self.0.lock().as_ref().expect("commit time").commit_time
     }
@@ -82,6 +99,30 @@ impl State {
         self.0.lock().as_mut().expect("dirty state").dirty_state = Some(state);
     }
 
+    // Only ticks this fastforward commits are hashed, each at most once.
+    pub fn wants_state_hash(&self, current_tick: u32) -> bool {
+        let inner = self.0.lock();
+        let inner = inner.as_ref().expect("state hash");
+        let interval = match inner.state_hash_interval {
+            Some(interval) => interval,
+            None => {
+                return false;
+            }
+        };
+        current_tick % interval == 0
+            && current_tick > inner.last_state_hash_tick
+            && current_tick <= inner.commit_time
+    }
+
+    pub fn add_state_hash(&self, tick: u32, state: mgba::state::State) {
+        let mut inner = self.0.lock();
+        let inner = inner.as_mut().expect("state hash");
+        inner.last_state_hash_tick = tick;
+        inner
+            .state_hashes
+            .push((tick, replay::state_hash(&state), state));
+    }
+
     pub fn peek_input_pair(&self) -> Option<input::Pair<input::Input>> {
         self.0
             .lock()
@@ -92,6 +133,15 @@ impl State {
             .cloned()
     }
 
+    pub fn push_input_pair(&self, ip: input::Pair<input::Input>) {
+        self.0
+            .lock()
+            .as_mut()
+            .expect("input pairs")
+            .input_pairs
+            .push_back(ip);
+    }
+
     pub fn pop_input_pair(&self) -> Option<input::Pair<input::Input>> {
         self.0
             .lock()
@@ -105,6 +155,10 @@ impl State {
         self.0.lock().as_mut().expect("error").result = Err(err);
     }
 
+    pub fn take_error(&self) -> Option<anyhow::Error> {
+        std::mem::replace(&mut self.0.lock().as_mut().expect("error").result, Ok(())).err()
+    }
+
     pub fn local_player_index(&self) -> u8 {
         self.0
             .lock()
@@ -137,6 +191,7 @@ impl Fastforwarder {
         rom_path: &std::path::Path,
         hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
         local_player_index: u8,
+        input_predictor: Box<dyn input::InputPredictor + Send>,
     ) -> anyhow::Result<Self> {
         let mut core = {
             let mut core = mgba::core::Core::new_gba("tango")?;
@@ -155,9 +210,45 @@ impl Fastforwarder {
             state,
             hooks,
             local_player_index,
+            input_predictor,
+            predictions: 0,
+            mispredictions: 0,
+            state_hash_interval: None,
+            state_hashes: vec![],
         })
     }
 
+    // Checks a newly committed remote input against what we would have predicted from the one right before it.
+    pub fn record_prediction(
+        &mut self,
+        last_committed_remote_input: &input::Input,
+        ip: &input::Pair<input::Input>,
+    ) {
+        let predicted = self
+            .input_predictor
+            .predict(&ip.local, last_committed_remote_input);
+        self.predictions += 1;
+        if predicted.joyflags != ip.remote.joyflags
+            || predicted.custom_screen_state != ip.remote.custom_screen_state
+        {
+            self.mispredictions += 1;
+        }
+    }
+
+    pub fn prediction_stats(&self) -> (usize, usize) {
+        (self.predictions, self.mispredictions)
+    }
+
+    // While set, the shared state is hashed whenever a committed tick lands on a multiple of the interval.
+    pub fn set_state_hash_interval(&mut self, interval: u32) {
+        self.state_hash_interval = Some(interval);
+    }
+
+    // Takes the (tick, hash, state) of every hash taken by fastforwards since the last call, in tick order.
+    pub fn take_state_hashes(&mut self) -> Vec<(u32, u32, mgba::state::State)> {
+        std::mem::take(&mut self.state_hashes)
+    }
+
     pub fn fastforward(
         &mut self,
         state: &mgba::state::State,
@@ -173,31 +264,10 @@ impl Fastforwarder {
             .iter()
             .cloned()
             .chain(local_player_inputs_left.iter().cloned().map(|local| {
-                let local_tick = local.local_tick;
-                let remote_tick = local.remote_tick;
-                input::Pair {
-                    local,
-                    remote: input::Input {
-                        local_tick,
-                        remote_tick,
-                        joyflags: {
-                            let mut joyflags = 0;
-                            if last_committed_remote_input.joyflags & mgba::input::keys::A as u16
-                                != 0
-                            {
-                                joyflags |= mgba::input::keys::A as u16;
-                            }
-                            if last_committed_remote_input.joyflags & mgba::input::keys::B as u16
-                                != 0
-                            {
-                                joyflags |= mgba::input::keys::B as u16;
-                            }
-                            joyflags
-                        },
-                        custom_screen_state: last_committed_remote_input.custom_screen_state,
-                        turn: vec![],
-                    },
-                }
+                let remote = self
+                    .input_predictor
+                    .predict(&local, &last_committed_remote_input);
+                input::Pair { local, remote }
             }))
             .collect::<Vec<input::Pair<input::Input>>>();
         let last_input = input_pairs.last().expect("last input pair").clone();
@@ -209,13 +279,16 @@ impl Fastforwarder {
         let commit_time = start_current_tick + commit_pairs.len() as u32;
         let dirty_time = start_current_tick + input_pairs.len() as u32 - 1;
 
-        *self.state.0.lock() = Some(InnerState::new(
+        let mut inner_state = InnerState::new(
             self.local_player_index,
             input_pairs,
             commit_time,
             dirty_time,
             Box::new(|| {}),
-        ));
+        );
+        inner_state.state_hash_interval = self.state_hash_interval;
+        inner_state.last_state_hash_tick = start_current_tick;
+        *self.state.0.lock() = Some(inner_state);
 
         loop {
             {
@@ -223,6 +296,7 @@ impl Fastforwarder {
                 let mut inner_state = inner_state_guard.as_mut().unwrap();
                 if inner_state.committed_state.is_some() && inner_state.dirty_state.is_some() {
                     let state = inner_state_guard.take().expect("state");
+                    self.state_hashes.extend(state.state_hashes);
                     return Ok((
                         state.committed_state.expect("committed state"),
                         state.dirty_state.expect("dirty state"),
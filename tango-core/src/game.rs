@@ -1,4 +1,4 @@
-use crate::{audio, battle, facade, gui, hooks, ipc, negotiation, tps};
+use crate::{audio, battle, facade, gui, hooks, ipc, negotiation, protocol, tps};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
 use parking_lot::Mutex;
 use std::sync::Arc;
@@ -23,6 +23,7 @@ pub struct Game {
     rt: tokio::runtime::Runtime,
     gui: gui::Gui,
     ipc_client: ipc::Client,
+    match_: Arc<tokio::sync::Mutex<Option<Arc<battle::Match>>>>,
     fps_counter: Arc<Mutex<tps::Counter>>,
     event_loop: Option<winit::event_loop::EventLoop<UserEvent>>,
     _audio_device: cpal::Device,
@@ -33,16 +34,18 @@ pub struct Game {
     _stream: cpal::Stream,
     joyflags: Arc<std::sync::atomic::AtomicU32>,
     keymapping: Keymapping,
-    _thread: mgba::thread::Thread,
+    thread: mgba::thread::Thread,
 }
 
-enum UserEvent {
+pub enum UserEvent {
     Gilrs(gilrs::Event),
+    Shutdown,
 }
 
 impl Game {
     pub fn new(
         mut ipc_client: ipc::Client,
+        mut ipc_receiver: ipc::Receiver,
         window_title: String,
         keymapping: Keymapping,
         rom_path: std::path::PathBuf,
@@ -63,15 +66,60 @@ impl Game {
 
         let handle = rt.handle().clone();
 
+        // The ROM has to be loaded before negotiating, so we can check it against the remote's.
+        let mut core = mgba::core::Core::new_gba("tango")?;
+        core.enable_video_buffer();
+
+        let rom_vf = mgba::vfile::VFile::open(&rom_path, mgba::vfile::flags::O_RDONLY)?;
+        core.as_mut().load_rom(rom_vf)?;
+
         let negotiation = if let Some(match_settings) = match_settings.as_ref() {
+            let game = negotiation::GameInfo::from_core(core.as_ref());
             Some(handle.block_on(async {
-                negotiation::negotiate(
-                    &mut ipc_client,
-                    &match_settings.session_id,
-                    &match_settings.matchmaking_connect_addr,
-                    &match_settings.ice_servers,
-                )
-                .await
+                let cancelled = async {
+                    loop {
+                        match ipc_receiver.receive_request().await {
+                            Ok(Some(ipc::Request::CancelNegotiation)) => {
+                                return;
+                            }
+                            Ok(None) => {
+                                // The frontend isn't talking to us, so it can't cancel either.
+                                std::future::pending::<()>().await;
+                            }
+                            Err(e) => {
+                                // The read side is broken for good, so trying again would just spin.
+                                log::warn!("failed to read ipc request, no longer listening for cancellation: {}", e);
+                                std::future::pending::<()>().await;
+                            }
+                        }
+                    }
+                };
+                let r = tokio::select! {
+                    r = negotiation::negotiate(
+                        &mut ipc_client,
+                        &match_settings.nickname,
+                        &game,
+                        match_settings.match_type,
+                        &match_settings.compatible_games,
+                        match_settings.input_delay,
+                        match_settings.max_queue_length,
+                        &match_settings.session_id,
+                        &match_settings.matchmaking_connect_addr,
+                        &match_settings.ice_servers,
+                        &match_settings.negotiation_timeouts,
+                    ) => r,
+                    _ = cancelled => Err(negotiation::Error::Cancelled),
+                };
+                match r {
+                    Ok(negotiation) => Ok(negotiation),
+                    Err(e) => {
+                        log::error!("negotiation failed: {}", e);
+                        let _ = ipc_client
+                            .send_notification(ipc::Notification::NegotiationFailed((&e).into()))
+                            .await;
+                        Err(e)
+                    }
+                }
             })?)
         } else {
             None
@@ -119,12 +167,6 @@ impl Game {
             (pixels, gui)
         };
 
-        let mut core = mgba::core::Core::new_gba("tango")?;
-        core.enable_video_buffer();
-
-        let rom_vf = mgba::vfile::VFile::open(&rom_path, mgba::vfile::flags::O_RDONLY)?;
-        core.as_mut().load_rom(rom_vf)?;
-
         let save_vf = mgba::vfile::VFile::open(
             &save_path,
             mgba::vfile::flags::O_CREAT | mgba::vfile::flags::O_RDWR,
@@ -144,7 +186,12 @@ impl Game {
         if let Some(_) = match_settings {
             core.set_traps(hooks.primary_traps(
                 handle.clone(),
-                facade::Facade::new(match_.clone(), joyflags.clone(), cancellation_token.clone()),
+                facade::Facade::new(
+                    match_.clone(),
+                    joyflags.clone(),
+                    cancellation_token.clone(),
+                    event_loop.as_ref().expect("event loop").create_proxy(),
+                ),
             ));
         }
 
@@ -173,18 +220,29 @@ impl Game {
                     negotiation.peer_conn,
                     negotiation.dc,
                     negotiation.rng,
+                    &negotiation.rng_commitment,
+                    negotiation.remote_nickname,
+                    negotiation.remote_game,
+                    negotiation.input_delay,
+                    negotiation.max_queue_length,
                     is_offerer,
                     thread.handle(),
+                    ipc_client.clone(),
                     match_settings,
-                )));
-            });
+                )?));
+                anyhow::Result::<()>::Ok(())
+            })?;
 
             handle.spawn(async move {
                 {
                     let match_ = match_.lock().await.clone().unwrap();
                     tokio::select! {
-                        Err(e) = match_.run() => {
-                            log::info!("match thread ending: {:?}", e);
+                        r = match_.run() => {
+                            if let Err(e) = r {
+                                log::info!("match thread ending: {:?}", e);
+                            } else {
+                                log::info!("match thread ending: match is over");
+                            }
                         }
                         _ = cancellation_token.cancelled() => {
                         }
@@ -251,6 +309,8 @@ impl Game {
                                                     local_delay: round.local_delay(),
                                                     remote_delay: round.remote_delay(),
                                                     tps_adjustment: round.tps_adjustment(),
+                                                    predictions: round.prediction_stats().0,
+                                                    mispredictions: round.prediction_stats().1,
                                                 }),
                                                 None => None,
                                             }
@@ -270,6 +330,7 @@ impl Game {
             rt,
             gui,
             ipc_client,
+            match_,
             _audio_device: audio_device,
             _primary_mux_handle: primary_mux_handle,
             keymapping,
@@ -280,7 +341,7 @@ impl Game {
             vbuf,
             _stream: stream,
             joyflags,
-            _thread: thread,
+            thread,
         })
     }
 
@@ -390,6 +451,16 @@ impl Game {
                                 }
                             }
                             winit::event::WindowEvent::CloseRequested => {
+                                self.thread.handle().pause();
+                                self.rt.block_on(async {
+                                    if let Some(match_) = self.match_.lock().await.clone() {
+                                        if let Err(e) =
+                                            match_.shutdown(protocol::GoodbyeReason::Quit).await
+                                        {
+                                            log::warn!("failed to leave match cleanly: {}", e);
+                                        }
+                                    }
+                                });
                                 *control_flow = winit::event_loop::ControlFlow::Exit;
                             }
                             winit::event::WindowEvent::Resized(size) => {
@@ -418,6 +489,12 @@ impl Game {
                     winit::event::Event::UserEvent(UserEvent::Gilrs(gilrs_ev)) => {
                         log::info!("{:?}", gilrs_ev);
                     }
+                    winit::event::Event::UserEvent(UserEvent::Shutdown) => {
+                        log::info!("shutting down");
+                        // The core has to stop before the runtime its traps block on is torn down.
+                        self.thread.handle().pause();
+                        *control_flow = winit::event_loop::ControlFlow::Exit;
+                    }
                     _ => {}
                 }
             });
@@ -107,6 +107,8 @@ pub struct RoundDebugStats {
     pub local_delay: u32,
     pub remote_delay: u32,
     pub tps_adjustment: i32,
+    pub predictions: usize,
+    pub mispredictions: usize,
 }
 
 pub struct MatchDebugStats {
@@ -177,6 +179,14 @@ impl State {
                                         battle_debug_stats.remote_delay,
                                     ));
                                     ui.end_row();
+
+                                    ui.label("Mispredictions");
+                                    ui.label(format!(
+                                        "{} / {}",
+                                        battle_debug_stats.mispredictions,
+                                        battle_debug_stats.predictions,
+                                    ));
+                                    ui.end_row();
                                 }
                             }
                         });
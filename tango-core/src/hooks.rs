@@ -34,4 +34,7 @@ pub trait Hooks {
     fn prepare_for_fastforward(&self, core: mgba::core::CoreMutRef);
 
     fn current_tick(&self, core: mgba::core::CoreMutRef) -> u32;
+
+    // This must only cover state that is identical from both players' perspectives, so replays can be checked against each other. Live desync detection instead compares full states from player 0's perspective.
+    fn shared_state_hash(&self, core: mgba::core::CoreMutRef) -> u32;
 }
@@ -163,6 +163,9 @@ impl hooks::Hooks for BN6 {
                                 };
 
                                 let mut round_state = match_.lock_round_state().await;
+                                if !round_state.is_active() {
+                                    return;
+                                }
 
                                 let local_init = munger.tx_buf(core);
                                 round_state.send_init(&local_init).await;
@@ -206,6 +209,9 @@ impl hooks::Hooks for BN6 {
                             };
 
                             let mut round_state = match_.lock_round_state().await;
+                            if !round_state.is_active() {
+                                return;
+                            }
 
                             log::info!("turn data marshaled on {}", munger.current_tick(core));
                             let local_turn = munger.tx_buf(core);
@@ -248,6 +254,17 @@ impl hooks::Hooks for BN6 {
                                     log::info!("battle state committed");
                                 }
 
+                                if !round_state.can_add_local_input() {
+                                    // We're too far ahead of the remote: hold the core here until its inputs catch up.
+                                    drop(round_state);
+                                    log::warn!("local input buffer full, waiting for remote");
+                                    if !match_.wait_for_remote_input().await {
+                                        log::error!("timed out waiting for remote input");
+                                        break 'abort;
+                                    }
+                                    continue;
+                                }
+
                                 let turn = round_state.take_local_pending_turn();
 
                                 if !round_state
@@ -382,7 +399,11 @@ impl hooks::Hooks for BN6 {
                                 return;
                             }
 
-                            round_state.end_round().await;
+                            if round_state.end_round().await {
+                                drop(round_state);
+                                log::info!("set decided, ending match");
+                                facade.end_match().await;
+                            }
                         });
                     }),
                 )
@@ -421,6 +442,10 @@ impl hooks::Hooks for BN6 {
                             };
 
                             let round_state = match_.lock_round_state().await;
+                            if !round_state.is_active() {
+                                return;
+                            }
+
                             core.gba_mut()
                                 .cpu_mut()
                                 .set_gpr(0, round_state.local_player_index() as i32);
@@ -443,6 +468,10 @@ impl hooks::Hooks for BN6 {
                             };
 
                             let round_state = match_.lock_round_state().await;
+                            if !round_state.is_active() {
+                                return;
+                            }
+
                             core.gba_mut()
                                 .cpu_mut()
                                 .set_gpr(0, round_state.local_player_index() as i32);
@@ -555,6 +584,13 @@ impl hooks::Hooks for BN6 {
                             );
                         }
 
+                        if ff_state.wants_state_hash(current_tick) {
+                            ff_state.add_state_hash(
+                                current_tick,
+                                core.save_state().expect("save hashed state"),
+                            );
+                        }
+
                         let ip = match ff_state.peek_input_pair() {
                             Some(ip) => ip,
                             None => {
@@ -797,4 +833,8 @@ impl hooks::Hooks for BN6 {
     fn current_tick(&self, core: mgba::core::CoreMutRef) -> u32 {
         self.munger.current_tick(core)
     }
+
+    fn shared_state_hash(&self, core: mgba::core::CoreMutRef) -> u32 {
+        self.munger.shared_state_hash(core)
+    }
 }
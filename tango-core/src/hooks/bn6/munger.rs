@@ -94,4 +94,23 @@ impl Munger {
     pub(super) fn current_tick(&self, mut core: mgba::core::CoreMutRef) -> u32 {
         core.raw_read_32(self.offsets.ewram.battle_state + 0x60, -1)
     }
+
+    // Everything here is read by absolute player index, so it comes out the same from either player's perspective.
+    pub(super) fn shared_state_hash(&self, mut core: mgba::core::CoreMutRef) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&self.current_tick(core).to_le_bytes());
+        hasher.update(
+            &core
+                .raw_read_32(self.offsets.ewram.rng2_state, -1)
+                .to_le_bytes(),
+        );
+        for index in 0..2 {
+            hasher.update(&core.raw_read_range::<0x08>(
+                self.offsets.ewram.player_input_data_arr + index * 0x08,
+                -1,
+            ));
+            hasher.update(&[core.raw_read_8(self.offsets.ewram.battle_state + 0x14 + index, -1)]);
+        }
+        hasher.finalize()
+    }
 }
@@ -13,6 +13,42 @@ impl Input {
     }
 }
 
+// Guesses what the remote pressed on ticks we don't have its input for yet. Turns are never predicted.
+pub trait InputPredictor {
+    fn predict(&self, local: &Input, last_committed_remote_input: &Input) -> Input;
+}
+
+// Repeats only A and B, since those are the buttons most likely to be held down for charging.
+pub struct ABInputPredictor;
+
+impl InputPredictor for ABInputPredictor {
+    fn predict(&self, local: &Input, last_committed_remote_input: &Input) -> Input {
+        Input {
+            local_tick: local.local_tick,
+            remote_tick: local.remote_tick,
+            joyflags: last_committed_remote_input.joyflags
+                & (mgba::input::keys::A | mgba::input::keys::B) as u16,
+            custom_screen_state: last_committed_remote_input.custom_screen_state,
+            turn: vec![],
+        }
+    }
+}
+
+// Repeats everything that was held down, including the d-pad.
+pub struct HeldInputPredictor;
+
+impl InputPredictor for HeldInputPredictor {
+    fn predict(&self, local: &Input, last_committed_remote_input: &Input) -> Input {
+        Input {
+            local_tick: local.local_tick,
+            remote_tick: local.remote_tick,
+            joyflags: last_committed_remote_input.joyflags,
+            custom_screen_state: last_committed_remote_input.custom_screen_state,
+            turn: vec![],
+        }
+    }
+}
+
 pub struct PairQueue<T>
 where
     T: Clone,
@@ -55,6 +91,10 @@ where
         self.local_delay
     }
 
+    pub fn set_local_delay(&mut self, local_delay: u32) {
+        self.local_delay = local_delay;
+    }
+
     pub fn local_queue_length(&self) -> usize {
         self.local_queue.len()
     }
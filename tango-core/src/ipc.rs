@@ -1,3 +1,4 @@
+use tokio::io::AsyncBufReadExt;
 use tokio::io::AsyncWriteExt;
 
 use crate::game;
@@ -14,12 +15,33 @@ pub struct Args {
 #[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
 pub struct MatchSettings {
     pub session_id: String,
-    pub input_delay: u32,
+    pub input_delay: Option<u32>,
     pub match_type: u16,
+    pub nickname: String,
     pub replays_path: String,
-    pub replay_metadata: String,
     pub matchmaking_connect_addr: String,
     pub ice_servers: Vec<String>,
+    #[serde(default)]
+    pub replay_telemetry: bool,
+    pub replay_signing_key_path: Option<String>,
+    pub spectator_listen_addr: Option<String>,
+    pub spectator_token: Option<String>,
+    pub best_of: Option<u32>,
+    pub max_queue_length: Option<u32>,
+    pub input_predictor: Option<InputPredictor>,
+    #[serde(default)]
+    pub compatible_games: Vec<Vec<String>>,
+    pub signalling_timeout_ms: Option<u32>,
+    pub ice_connect_timeout_ms: Option<u32>,
+    pub handshake_timeout_ms: Option<u32>,
+}
+
+#[derive(
+    Clone, Copy, Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef,
+)]
+pub enum InputPredictor {
+    AB,
+    Held,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
@@ -64,6 +86,117 @@ impl Args {
 #[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
 pub enum Notification {
     State(State),
+    InputDelay(InputDelay),
+    Desync(Desync),
+    RoundEnded(RoundEnded),
+    MatchEnded(MatchEnded),
+    RemoteForfeited(RemoteForfeited),
+    RemoteLeft(RemoteLeft),
+    NegotiationProgress(NegotiationProgress),
+    NegotiationFailed(NegotiationFailure),
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub enum NegotiationProgress {
+    Signalling,
+    PeerConnectionStateChanged(PeerConnectionState),
+    Handshaking,
+    Ready,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub enum PeerConnectionState {
+    New,
+    Connecting,
+    Connected,
+    Disconnected,
+    Failed,
+    Closed,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub enum NegotiationFailure {
+    ProtocolVersionMismatch,
+    MatchTypeMismatch,
+    IncompatibleGames,
+    IdenticalCommitment,
+    InvalidCommitment,
+    UnexpectedPacket,
+    ChannelClosed,
+    Timeout,
+    Cancelled,
+    SessionFull,
+    Unknown,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub struct InputDelay {
+    pub input_delay: u32,
+    pub rtt_ms: u32,
+    pub jitter_ms: u32,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub struct Desync {
+    pub round_number: u8,
+    pub tick: u32,
+    pub state_path: Option<String>,
+}
+
+#[derive(
+    Clone, Copy, Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef,
+)]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+#[derive(
+    Clone, Default, Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef,
+)]
+pub struct Score {
+    pub local_wins: u32,
+    pub remote_wins: u32,
+    pub draws: u32,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub struct RoundEnded {
+    pub round_number: u8,
+    pub winner: Option<Side>,
+    pub score: Score,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub struct MatchEnded {
+    pub winner: Option<Side>,
+    pub score: Score,
+    pub reason: MatchEndReason,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub enum MatchEndReason {
+    Completed,
+    RemoteForfeited,
+    RemoteLeft(GoodbyeReason),
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub struct RemoteForfeited {
+    pub round_number: u8,
+}
+
+#[derive(
+    Clone, Copy, Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef,
+)]
+pub enum GoodbyeReason {
+    MatchEnded,
+    Quit,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub struct RemoteLeft {
+    pub reason: GoodbyeReason,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
@@ -73,6 +206,11 @@ pub enum State {
     Connecting,
 }
 
+#[derive(Debug, serde::Serialize, serde::Deserialize, typescript_type_def::TypeDef)]
+pub enum Request {
+    CancelNegotiation,
+}
+
 #[derive(Clone)]
 pub struct Client {
     writer:
@@ -94,3 +232,30 @@ impl Client {
         Ok(())
     }
 }
+
+pub struct Receiver {
+    reader:
+        tokio::io::Lines<tokio::io::BufReader<std::pin::Pin<Box<dyn tokio::io::AsyncRead + Send>>>>,
+}
+
+impl Receiver {
+    pub fn new_from_stdin() -> Self {
+        Receiver {
+            reader: tokio::io::BufReader::new(
+                Box::pin(tokio::io::stdin()) as std::pin::Pin<Box<dyn tokio::io::AsyncRead + Send>>
+            )
+            .lines(),
+        }
+    }
+
+    // Returns None once the frontend closes our stdin.
+    pub async fn receive_request(&mut self) -> anyhow::Result<Option<Request>> {
+        let line = match self.reader.next_line().await? {
+            Some(line) => line,
+            None => {
+                return Ok(None);
+            }
+        };
+        Ok(Some(serde_json::from_str(&line)?))
+    }
+}
@@ -25,11 +25,35 @@ fn main() -> Result<(), anyhow::Error> {
             Ok::<_, anyhow::Error>(tango_core::battle::Settings {
                 matchmaking_connect_addr: s.matchmaking_connect_addr,
                 session_id: s.session_id,
-                replay_metadata: s.replay_metadata.into(),
+                nickname: s.nickname,
                 replays_path: s.replays_path.into(),
                 match_type: s.match_type,
                 input_delay: s.input_delay,
                 ice_servers: s.ice_servers,
+                replay_telemetry: s.replay_telemetry,
+                replay_signing_key_path: s.replay_signing_key_path.map(|p| p.into()),
+                spectator_listen_addr: s.spectator_listen_addr,
+                spectator_token: s.spectator_token,
+                best_of: s.best_of,
+                max_queue_length: s
+                    .max_queue_length
+                    .unwrap_or(tango_core::battle::DEFAULT_MAX_QUEUE_LENGTH),
+                input_predictor: s.input_predictor,
+                compatible_games: s.compatible_games,
+                negotiation_timeouts: {
+                    let defaults = tango_core::negotiation::Timeouts::default();
+                    tango_core::negotiation::Timeouts {
+                        signalling: s.signalling_timeout_ms.map_or(defaults.signalling, |ms| {
+                            std::time::Duration::from_millis(ms as u64)
+                        }),
+                        ice_connect: s.ice_connect_timeout_ms.map_or(defaults.ice_connect, |ms| {
+                            std::time::Duration::from_millis(ms as u64)
+                        }),
+                        handshake: s.handshake_timeout_ms.map_or(defaults.handshake, |ms| {
+                            std::time::Duration::from_millis(ms as u64)
+                        }),
+                    }
+                },
             })
         })
         .map_or(Ok(None), |r| r.map(Some))?;
@@ -37,6 +61,7 @@ fn main() -> Result<(), anyhow::Error> {
 
     let g = tango_core::game::Game::new(
         tango_core::ipc::Client::new_from_stdout(),
+        tango_core::ipc::Receiver::new_from_stdin(),
         args.window_title,
         args.keymapping.try_into()?,
         args.rom_path.into(),
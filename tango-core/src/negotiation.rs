@@ -1,4 +1,4 @@
-use crate::{ipc, protocol};
+use crate::{game, ipc, protocol};
 use rand::Rng;
 use rand::SeedableRng;
 use sha3::digest::ExtendableOutput;
@@ -10,16 +10,82 @@ pub struct Negotiation {
     pub dc: datachannel_wrapper::DataChannel,
     pub peer_conn: datachannel_wrapper::PeerConnection,
     pub rng: rand_pcg::Mcg128Xsl64,
+    pub rng_commitment: Vec<u8>,
+    pub remote_nickname: String,
+    pub input_delay: u32,
+    pub max_queue_length: u32,
+    pub remote_game: GameInfo,
+}
+
+#[derive(Clone, Debug)]
+pub struct GameInfo {
+    pub title: String,
+    pub code: String,
+    pub rom_crc32: u32,
+}
+
+impl GameInfo {
+    pub fn from_core(core: mgba::core::CoreRef) -> Self {
+        GameInfo {
+            title: core.game_title(),
+            code: core.game_code(),
+            rom_crc32: core.crc32(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    pub signalling: std::time::Duration,
+    pub ice_connect: std::time::Duration,
+    pub handshake: std::time::Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            signalling: std::time::Duration::from_secs(5 * 60),
+            ice_connect: std::time::Duration::from_secs(30),
+            handshake: std::time::Duration::from_secs(30),
+        }
+    }
+}
+
+// Games in the same group can be matched against each other even though their ROMs differ, e.g. different versions or regions of the same game. Otherwise, both sides must have the exact same ROM: same title, same game code and same CRC32.
+fn are_games_compatible(
+    compatible_games: &[Vec<String>],
+    local: &GameInfo,
+    remote: &GameInfo,
+) -> bool {
+    if local.title == remote.title
+        && local.code == remote.code
+        && local.rom_crc32 == remote.rom_crc32
+    {
+        return true;
+    }
+
+    compatible_games
+        .iter()
+        .any(|group| group.contains(&local.title) && group.contains(&remote.title))
 }
 
 #[derive(Debug)]
 pub enum Error {
     ExpectedHello,
     ExpectedHola,
+    ExpectedPong,
+    ExpectedInputDelayProposal,
     IdenticalCommitment,
     ProtocolVersionMismatch,
     MatchTypeMismatch,
+    IncompatibleGames,
     InvalidCommitment,
+    SignallingTimeout,
+    IceConnectTimeout,
+    HandshakeTimeout,
+    ChannelClosed,
+    Deserialize(bincode::Error),
+    Cancelled,
     Other(anyhow::Error),
 }
 
@@ -46,10 +112,19 @@ impl std::fmt::Display for Error {
         match self {
             Error::ExpectedHello => write!(f, "expected hello"),
             Error::ExpectedHola => write!(f, "expected hola"),
+            Error::ExpectedPong => write!(f, "expected pong"),
+            Error::ExpectedInputDelayProposal => write!(f, "expected input delay proposal"),
             Error::IdenticalCommitment => write!(f, "identical commitment"),
             Error::ProtocolVersionMismatch => write!(f, "protocol version mismatch"),
             Error::MatchTypeMismatch => write!(f, "match type mismatch"),
+            Error::IncompatibleGames => write!(f, "game mismatch"),
             Error::InvalidCommitment => write!(f, "invalid commitment"),
+            Error::SignallingTimeout => write!(f, "timed out waiting for signalling"),
+            Error::IceConnectTimeout => write!(f, "timed out waiting for ice to connect"),
+            Error::HandshakeTimeout => write!(f, "timed out waiting for handshake"),
+            Error::ChannelClosed => write!(f, "data channel closed"),
+            Error::Deserialize(e) => write!(f, "failed to deserialize packet: {}", e),
+            Error::Cancelled => write!(f, "cancelled"),
             Error::Other(e) => write!(f, "other error: {}", e),
         }
     }
@@ -57,6 +132,43 @@ impl std::fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+impl From<&Error> for ipc::NegotiationFailure {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::ExpectedHello
+            | Error::ExpectedHola
+            | Error::ExpectedPong
+            | Error::ExpectedInputDelayProposal => ipc::NegotiationFailure::UnexpectedPacket,
+            Error::IdenticalCommitment => ipc::NegotiationFailure::IdenticalCommitment,
+            Error::ProtocolVersionMismatch => ipc::NegotiationFailure::ProtocolVersionMismatch,
+            Error::MatchTypeMismatch => ipc::NegotiationFailure::MatchTypeMismatch,
+            Error::IncompatibleGames => ipc::NegotiationFailure::IncompatibleGames,
+            Error::InvalidCommitment => ipc::NegotiationFailure::InvalidCommitment,
+            Error::SignallingTimeout | Error::IceConnectTimeout | Error::HandshakeTimeout => {
+                ipc::NegotiationFailure::Timeout
+            }
+            Error::ChannelClosed => ipc::NegotiationFailure::ChannelClosed,
+            Error::Deserialize(_) => ipc::NegotiationFailure::UnexpectedPacket,
+            Error::Cancelled => ipc::NegotiationFailure::Cancelled,
+            Error::Other(e) => match e.downcast_ref::<tango_matchmaking::protocol::Error>() {
+                Some(tango_matchmaking::protocol::Error::ProtocolVersionMismatch { .. }) => {
+                    ipc::NegotiationFailure::ProtocolVersionMismatch
+                }
+                Some(tango_matchmaking::protocol::Error::SessionFull) => {
+                    ipc::NegotiationFailure::SessionFull
+                }
+                Some(tango_matchmaking::protocol::Error::SessionExpired) => {
+                    ipc::NegotiationFailure::Timeout
+                }
+                Some(tango_matchmaking::protocol::Error::UnexpectedPacket) => {
+                    ipc::NegotiationFailure::UnexpectedPacket
+                }
+                None => ipc::NegotiationFailure::Unknown,
+            },
+        }
+    }
+}
+
 fn make_rng_commitment(nonce: &[u8]) -> std::io::Result<[u8; 32]> {
     let mut shake128 = sha3::Shake128::default();
     shake128.write_all(b"syncrand:nonce:")?;
@@ -70,20 +182,159 @@ fn make_rng_commitment(nonce: &[u8]) -> std::io::Result<[u8; 32]> {
     Ok(commitment)
 }
 
-pub async fn negotiate(
-    ipc_client: &mut ipc::Client,
-    session_id: &str,
-    matchmaking_connect_addr: &str,
-    ice_servers: &[String],
-) -> Result<Negotiation, Error> {
-    log::info!("negotiating match, session_id = {}", session_id);
-    ipc_client
-        .send_notification(ipc::Notification::State(ipc::State::Waiting))
+const PING_COUNT: usize = 10;
+pub const MIN_AUTO_INPUT_DELAY: u32 = 2;
+pub const MAX_AUTO_INPUT_DELAY: u32 = 10;
+
+async fn receive_packet(
+    dc_rx: &mut datachannel_wrapper::DataChannelReceiver,
+    deadline: tokio::time::Instant,
+) -> Result<protocol::Packet, Error> {
+    let raw = tokio::time::timeout_at(deadline, dc_rx.receive())
+        .await
+        .map_err(|_| Error::HandshakeTimeout)?
+        .ok_or(Error::ChannelClosed)?;
+    protocol::Packet::deserialize(raw.as_slice()).map_err(Error::Deserialize)
+}
+
+async fn send_packet(
+    dc_tx: &mut datachannel_wrapper::DataChannelSender,
+    packet: protocol::Packet,
+) -> Result<(), Error> {
+    dc_tx
+        .send(packet.serialize().expect("serialize").as_slice())
         .await?;
+    Ok(())
+}
+
+// Both sides ping each other at the same time, so we need to keep answering the remote's pings while waiting for our own pongs.
+//
+// If the remote finishes measuring first, it may send its input delay proposal while we're still waiting on a pong: that proposal is returned alongside the RTTs instead of being treated as an error.
+async fn measure_rtt(
+    dc_rx: &mut datachannel_wrapper::DataChannelReceiver,
+    dc_tx: &mut datachannel_wrapper::DataChannelSender,
+    deadline: tokio::time::Instant,
+) -> Result<(Vec<std::time::Duration>, Option<u32>), Error> {
+    let start = std::time::Instant::now();
+    let mut rtts = Vec::with_capacity(PING_COUNT);
+    let mut remote_input_delay = None;
+    for _ in 0..PING_COUNT {
+        let ts = start.elapsed().as_micros() as u64;
+        send_packet(dc_tx, protocol::Packet::Ping(protocol::Ping { ts })).await?;
+
+        loop {
+            match receive_packet(dc_rx, deadline).await? {
+                protocol::Packet::Ping(ping) => {
+                    send_packet(
+                        dc_tx,
+                        protocol::Packet::Pong(protocol::Pong { ts: ping.ts }),
+                    )
+                    .await?;
+                }
+                protocol::Packet::Pong(pong) if pong.ts == ts => {
+                    rtts.push(start.elapsed() - std::time::Duration::from_micros(ts));
+                    break;
+                }
+                protocol::Packet::InputDelayProposal(proposal) if remote_input_delay.is_none() => {
+                    remote_input_delay = Some(proposal.input_delay);
+                }
+                _ => {
+                    return Err(Error::ExpectedPong);
+                }
+            }
+        }
+    }
+    Ok((rtts, remote_input_delay))
+}
+
+fn select_input_delay(
+    rtts: &[std::time::Duration],
+) -> (std::time::Duration, std::time::Duration, u32) {
+    let mut sorted = rtts.to_vec();
+    sorted.sort();
+    let median = sorted[sorted.len() / 2];
 
-    let (mut peer_conn, signal_receiver) =
+    // Mean difference between consecutive samples, as in RFC 3550.
+    let jitter = rtts
+        .windows(2)
+        .map(|w| {
+            if w[1] > w[0] {
+                w[1] - w[0]
+            } else {
+                w[0] - w[1]
+            }
+        })
+        .sum::<std::time::Duration>()
+        / std::cmp::max(rtts.len() - 1, 1) as u32;
+
+    let frame_time = 1.0 / game::EXPECTED_FPS as f64;
+    let input_delay =
+        ((median.as_secs_f64() / 2.0 + jitter.as_secs_f64() * 2.0) / frame_time).ceil() as u32 + 1;
+
+    (
+        median,
+        jitter,
+        input_delay.clamp(MIN_AUTO_INPUT_DELAY, MAX_AUTO_INPUT_DELAY),
+    )
+}
+
+// If an IPC client is given, peer connection state changes are reported through it while signalling.
+async fn connect(
+    matchmaking_connect_addr: &str,
+    session_id: &str,
+    ice_servers: &[String],
+    timeouts: &Timeouts,
+    ipc_client: Option<ipc::Client>,
+) -> Result<
+    (
+        datachannel_wrapper::PeerConnection,
+        datachannel_wrapper::DataChannel,
+    ),
+    Error,
+> {
+    let (mut peer_conn, mut signal_receiver) =
         datachannel_wrapper::PeerConnection::new(datachannel_wrapper::RtcConfig::new(ice_servers))?;
 
+    if let Some(ipc_client) = ipc_client {
+        let (signal_tx, forwarded_signal_receiver) = tokio::sync::mpsc::channel(1);
+        tokio::task::spawn(async move {
+            while let Some(signal) = signal_receiver.recv().await {
+                if let datachannel_wrapper::PeerConnectionSignal::ConnectionStateChange(state) =
+                    &signal
+                {
+                    let _ = ipc_client
+                        .send_notification(ipc::Notification::NegotiationProgress(
+                            ipc::NegotiationProgress::PeerConnectionStateChanged(match state {
+                                datachannel_wrapper::ConnectionState::New => {
+                                    ipc::PeerConnectionState::New
+                                }
+                                datachannel_wrapper::ConnectionState::Connecting => {
+                                    ipc::PeerConnectionState::Connecting
+                                }
+                                datachannel_wrapper::ConnectionState::Connected => {
+                                    ipc::PeerConnectionState::Connected
+                                }
+                                datachannel_wrapper::ConnectionState::Disconnected => {
+                                    ipc::PeerConnectionState::Disconnected
+                                }
+                                datachannel_wrapper::ConnectionState::Failed => {
+                                    ipc::PeerConnectionState::Failed
+                                }
+                                datachannel_wrapper::ConnectionState::Closed => {
+                                    ipc::PeerConnectionState::Closed
+                                }
+                            }),
+                        ))
+                        .await;
+                }
+                if signal_tx.send(signal).await.is_err() {
+                    break;
+                }
+            }
+        });
+        signal_receiver = forwarded_signal_receiver;
+    }
+
     let dc = peer_conn.create_data_channel(
         "tango",
         datachannel_wrapper::DataChannelInit::default()
@@ -98,15 +349,24 @@ pub async fn negotiate(
             .stream(0),
     )?;
 
-    tango_matchmaking::client::connect(
-        &matchmaking_connect_addr,
-        &mut peer_conn,
-        signal_receiver,
-        &session_id,
+    let session = tokio::time::timeout(
+        timeouts.signalling,
+        tango_matchmaking::client::connect(
+            &matchmaking_connect_addr,
+            &mut peer_conn,
+            &mut signal_receiver,
+            &session_id,
+        ),
     )
-    .await?;
+    .await
+    .map_err(|_| Error::SignallingTimeout)??;
 
-    let (mut dc_rx, mut dc_tx) = dc.split();
+    tokio::time::timeout(
+        timeouts.ice_connect,
+        session.wait_for_connected(&mut peer_conn, &mut signal_receiver),
+    )
+    .await
+    .map_err(|_| Error::IceConnectTimeout)??;
 
     log::info!(
         "local sdp (type = {:?}): {}",
@@ -119,9 +379,77 @@ pub async fn negotiate(
         peer_conn.remote_description().expect("remote sdp").sdp
     );
 
+    Ok((peer_conn, dc))
+}
+
+// Sets up a fresh data channel with the same peer after the original one was dropped, by going through the matchmaking server with the same session ID again.
+pub async fn reconnect(
+    matchmaking_connect_addr: &str,
+    session_id: &str,
+    ice_servers: &[String],
+    timeouts: &Timeouts,
+) -> Result<
+    (
+        datachannel_wrapper::PeerConnection,
+        datachannel_wrapper::DataChannel,
+    ),
+    Error,
+> {
+    log::info!("reconnecting, session_id = {}", session_id);
+    connect(
+        matchmaking_connect_addr,
+        session_id,
+        ice_servers,
+        timeouts,
+        None,
+    )
+    .await
+}
+
+pub async fn negotiate(
+    ipc_client: &mut ipc::Client,
+    nickname: &str,
+    game: &GameInfo,
+    match_type: u16,
+    compatible_games: &[Vec<String>],
+    input_delay: Option<u32>,
+    max_queue_length: u32,
+    session_id: &str,
+    matchmaking_connect_addr: &str,
+    ice_servers: &[String],
+    timeouts: &Timeouts,
+) -> Result<Negotiation, Error> {
+    log::info!("negotiating match, session_id = {}", session_id);
+    ipc_client
+        .send_notification(ipc::Notification::State(ipc::State::Waiting))
+        .await?;
+    ipc_client
+        .send_notification(ipc::Notification::NegotiationProgress(
+            ipc::NegotiationProgress::Signalling,
+        ))
+        .await?;
+
+    let (peer_conn, dc) = connect(
+        matchmaking_connect_addr,
+        session_id,
+        ice_servers,
+        timeouts,
+        Some(ipc_client.clone()),
+    )
+    .await?;
+    let (mut dc_rx, mut dc_tx) = dc.split();
+
     ipc_client
         .send_notification(ipc::Notification::State(ipc::State::Connecting))
         .await?;
+    ipc_client
+        .send_notification(ipc::Notification::NegotiationProgress(
+            ipc::NegotiationProgress::Handshaking,
+        ))
+        .await?;
+    // Everything from here until we agree on input delay has to finish within the handshake timeout.
+    let deadline = tokio::time::Instant::now() + timeouts.handshake;
+
     let mut nonce = [0u8; 16];
     rand::rngs::OsRng {}.fill(&mut nonce);
     let commitment = make_rng_commitment(&nonce)?;
@@ -133,6 +461,12 @@ pub async fn negotiate(
             protocol::Packet::Hello(protocol::Hello {
                 protocol_version: protocol::VERSION,
                 rng_commitment: commitment.to_vec(),
+                nickname: nickname.to_string(),
+                max_queue_length,
+                game_title: game.title.clone(),
+                game_code: game.code.clone(),
+                rom_crc32: game.rom_crc32,
+                match_type,
             })
             .serialize()
             .expect("serialize")
@@ -140,17 +474,7 @@ pub async fn negotiate(
         )
         .await?;
 
-    let hello = match protocol::Packet::deserialize(
-        match dc_rx.receive().await {
-            Some(d) => d,
-            None => {
-                return Err(Error::ExpectedHello);
-            }
-        }
-        .as_slice(),
-    )
-    .map_err(|_| Error::ExpectedHello)?
-    {
+    let hello = match receive_packet(&mut dc_rx, deadline).await? {
         protocol::Packet::Hello(hello) => hello,
         _ => {
             return Err(Error::ExpectedHello);
@@ -167,6 +491,33 @@ pub async fn negotiate(
         return Err(Error::ProtocolVersionMismatch);
     }
 
+    if hello.match_type != match_type {
+        log::warn!(
+            "match type mismatch: ours = {:04x}, theirs = {:04x}",
+            match_type,
+            hello.match_type
+        );
+        return Err(Error::MatchTypeMismatch);
+    }
+
+    let remote_game = GameInfo {
+        title: hello.game_title.clone(),
+        code: hello.game_code.clone(),
+        rom_crc32: hello.rom_crc32,
+    };
+    if !are_games_compatible(compatible_games, game, &remote_game) {
+        log::warn!(
+            "incompatible games: ours = {:?}, theirs = {:?}",
+            game,
+            remote_game
+        );
+        return Err(Error::IncompatibleGames);
+    }
+
+    // Neither side may have more uncommitted inputs in flight than the other is willing to hold.
+    let max_queue_length = std::cmp::min(max_queue_length, hello.max_queue_length);
+    log::info!("agreed on max queue length = {}", max_queue_length);
+
     dc_tx
         .send(
             protocol::Packet::Hola(protocol::Hola {
@@ -178,17 +529,7 @@ pub async fn negotiate(
         )
         .await?;
 
-    let hola = match protocol::Packet::deserialize(
-        match dc_rx.receive().await {
-            Some(d) => d,
-            None => {
-                return Err(Error::ExpectedHola);
-            }
-        }
-        .as_slice(),
-    )
-    .map_err(|_| Error::ExpectedHola)?
-    {
+    let hola = match receive_packet(&mut dc_rx, deadline).await? {
         protocol::Packet::Hola(hola) => hola,
         _ => {
             return Err(Error::ExpectedHola);
@@ -201,7 +542,68 @@ pub async fn negotiate(
         return Err(Error::InvalidCommitment);
     }
 
+    let (rtts, early_remote_input_delay) = measure_rtt(&mut dc_rx, &mut dc_tx, deadline).await?;
+    let (rtt, jitter, auto_input_delay) = select_input_delay(&rtts);
+    log::info!(
+        "measured rtt = {:?}, jitter = {:?}, suggested input delay = {}",
+        rtt,
+        jitter,
+        auto_input_delay
+    );
+    let local_input_delay = input_delay.unwrap_or(auto_input_delay);
+
+    send_packet(
+        &mut dc_tx,
+        protocol::Packet::InputDelayProposal(protocol::InputDelayProposal {
+            input_delay: local_input_delay,
+        }),
+    )
+    .await?;
+
+    // The remote may still be pinging us before it sends its proposal, unless it already sent it while we were measuring.
+    let remote_input_delay = if let Some(remote_input_delay) = early_remote_input_delay {
+        remote_input_delay
+    } else {
+        loop {
+            match receive_packet(&mut dc_rx, deadline).await? {
+                protocol::Packet::Ping(ping) => {
+                    send_packet(
+                        &mut dc_tx,
+                        protocol::Packet::Pong(protocol::Pong { ts: ping.ts }),
+                    )
+                    .await?;
+                }
+                protocol::Packet::InputDelayProposal(proposal) => {
+                    break proposal.input_delay;
+                }
+                _ => {
+                    return Err(Error::ExpectedInputDelayProposal);
+                }
+            }
+        }
+    };
+
+    let input_delay = std::cmp::max(local_input_delay, remote_input_delay);
+    log::info!(
+        "agreed on input delay = {} (ours = {}, theirs = {})",
+        input_delay,
+        local_input_delay,
+        remote_input_delay
+    );
+    ipc_client
+        .send_notification(ipc::Notification::InputDelay(ipc::InputDelay {
+            input_delay,
+            rtt_ms: rtt.as_millis() as u32,
+            jitter_ms: jitter.as_millis() as u32,
+        }))
+        .await?;
+
     log::info!("connection ok!");
+    ipc_client
+        .send_notification(ipc::Notification::NegotiationProgress(
+            ipc::NegotiationProgress::Ready,
+        ))
+        .await?;
 
     let seed = hola
         .rng_nonce
@@ -213,6 +615,107 @@ pub async fn negotiate(
     Ok(Negotiation {
         dc: dc_rx.unsplit(dc_tx),
         peer_conn,
+        rng_commitment: make_rng_commitment(&seed)?.to_vec(),
         rng: rand_pcg::Mcg128Xsl64::from_seed(seed.try_into().expect("rng seed")),
+        remote_nickname: hello.nickname,
+        input_delay,
+        max_queue_length,
+        remote_game,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> std::time::Duration {
+        std::time::Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_select_input_delay() {
+        assert_eq!(
+            select_input_delay(&[ms(40), ms(60), ms(50)]),
+            (ms(50), ms(15), 5)
+        );
+    }
+
+    #[test]
+    fn test_select_input_delay_single_sample() {
+        assert_eq!(select_input_delay(&[ms(50)]), (ms(50), ms(0), 3));
+    }
+
+    #[test]
+    fn test_select_input_delay_bounds() {
+        assert_eq!(
+            select_input_delay(&[ms(0), ms(0), ms(0)]).2,
+            MIN_AUTO_INPUT_DELAY
+        );
+        assert_eq!(
+            select_input_delay(&[ms(5000), ms(5000), ms(5000)]).2,
+            MAX_AUTO_INPUT_DELAY
+        );
+        assert_eq!(
+            select_input_delay(&[ms(0), ms(2000), ms(0), ms(2000)]).2,
+            MAX_AUTO_INPUT_DELAY
+        );
+    }
+
+    fn game(title: &str, code: &str, rom_crc32: u32) -> GameInfo {
+        GameInfo {
+            title: title.to_string(),
+            code: code.to_string(),
+            rom_crc32,
+        }
+    }
+
+    #[test]
+    fn test_are_games_compatible_identical() {
+        let local = game("MEGAMAN6_FXX", "BR5E", 0x12345678);
+        assert!(are_games_compatible(&[], &local, &local.clone()));
+    }
+
+    #[test]
+    fn test_are_games_compatible_mismatch() {
+        let local = game("MEGAMAN6_FXX", "BR5E", 0x12345678);
+        assert!(!are_games_compatible(
+            &[],
+            &local,
+            &game("MEGAMAN6_FXX", "BR5E", 0x87654321)
+        ));
+        assert!(!are_games_compatible(
+            &[],
+            &local,
+            &game("MEGAMAN6_FXX", "BR5P", 0x12345678)
+        ));
+        assert!(!are_games_compatible(
+            &[],
+            &local,
+            &game("MEGAMAN6_GXX", "BR6E", 0x11111111)
+        ));
+    }
+
+    #[test]
+    fn test_are_games_compatible_groups() {
+        let compatible_games = vec![
+            vec!["MEGAMAN6_FXX".to_string(), "MEGAMAN6_GXX".to_string()],
+            vec!["ROCKEXE6_RXX".to_string(), "ROCKEXE6_GXX".to_string()],
+        ];
+        let falzar = game("MEGAMAN6_FXX", "BR5E", 0x12345678);
+        assert!(are_games_compatible(
+            &compatible_games,
+            &falzar,
+            &game("MEGAMAN6_GXX", "BR6E", 0x11111111)
+        ));
+        assert!(are_games_compatible(
+            &compatible_games,
+            &game("MEGAMAN6_GXX", "BR6E", 0x11111111),
+            &falzar
+        ));
+        assert!(!are_games_compatible(
+            &compatible_games,
+            &falzar,
+            &game("ROCKEXE6_GXX", "BR6J", 0x22222222)
+        ));
+    }
+}
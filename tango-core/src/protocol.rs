@@ -1,6 +1,6 @@
 use bincode::Options;
 
-pub const VERSION: u8 = 0x10;
+pub const VERSION: u8 = 0x1a;
 
 lazy_static! {
     static ref BINCODE_OPTIONS: bincode::config::WithOtherLimit<
@@ -11,15 +11,24 @@ lazy_static! {
         bincode::config::Bounded,
     > = bincode::DefaultOptions::new()
         .with_fixint_encoding()
-        .with_limit(128 * 1024);
+        .with_limit(256 * 1024);
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub enum Packet {
     Hello(Hello),
     Hola(Hola),
+    Ping(Ping),
+    Pong(Pong),
+    InputDelayProposal(InputDelayProposal),
     Init(Init),
     Input(Input),
+    StateCommitted(StateCommitted),
+    InputDelayChanged(InputDelayChanged),
+    Resume(Resume),
+    StateHash(StateHash),
+    Goodbye(Goodbye),
+    Forfeit(Forfeit),
 }
 
 impl Packet {
@@ -36,6 +45,12 @@ impl Packet {
 pub struct Hello {
     pub protocol_version: u8,
     pub rng_commitment: Vec<u8>,
+    pub nickname: String,
+    pub max_queue_length: u32,
+    pub game_title: String,
+    pub game_code: String,
+    pub rom_crc32: u32,
+    pub match_type: u16,
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
@@ -43,6 +58,21 @@ pub struct Hola {
     pub rng_nonce: Vec<u8>,
 }
 
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Ping {
+    pub ts: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Pong {
+    pub ts: u64,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct InputDelayProposal {
+    pub input_delay: u32,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
 pub struct Init {
     pub round_number: u8,
@@ -59,3 +89,46 @@ pub struct Input {
     pub custom_screen_state: u8,
     pub turn: Vec<u8>,
 }
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct StateCommitted {
+    pub round_number: u8,
+    pub state: Vec<u8>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct InputDelayChanged {
+    pub round_number: u8,
+    pub tick: u32,
+    pub input_delay: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Resume {
+    pub round_number: u8,
+    pub last_received_tick: Option<u32>,
+    pub input_delay: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct StateHash {
+    pub round_number: u8,
+    pub tick: u32,
+    pub hash: u32,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub enum GoodbyeReason {
+    MatchEnded,
+    Quit,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Goodbye {
+    pub reason: GoodbyeReason,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct Forfeit {
+    pub round_number: u8,
+}
@@ -1,28 +1,560 @@
+use crate::fastforwarder;
+use crate::hooks;
 use crate::input;
+use crate::protocol;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
+use ed25519_dalek::Signer;
+use ed25519_dalek::Verifier;
+use sha3::Digest;
 use std::io::Read;
 use std::io::Write;
 pub trait WriteSeek: std::io::Write + std::io::Seek {}
 impl<T: std::io::Write + std::io::Seek> WriteSeek for T {}
 
 pub struct Writer {
-    encoder: Option<zstd::stream::write::Encoder<'static, Box<dyn WriteSeek + Send>>>,
+    encoder:
+        Option<HashingWriter<zstd::stream::write::Encoder<'static, Box<dyn WriteSeek + Send>>>>,
+    start_position: u64,
     num_inputs: u32,
+    keyframe_interval: u32,
+    last_keyframe_input_index: u32,
+    state_hash_interval: u32,
+    last_state_hash_input_index: u32,
+    record_telemetry: bool,
+    signing_key: Option<std::sync::Arc<ed25519_dalek::Keypair>>,
+}
+
+// Signatures cover everything in the decompressed stream up to the signature frame.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: sha3::Sha3_256,
+}
+
+impl<W: std::io::Write> std::io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct HashingReader<R> {
+    inner: R,
+    hasher: sha3::Sha3_256,
+}
+
+impl<R: std::io::Read> std::io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
 }
 
 const HEADER: &[u8] = b"TOOT";
-const VERSION: u8 = 0x0f;
+const MATCH_HEADER: &[u8] = b"TOOM";
+const MATCH_VERSION: u8 = 0x01;
+
+const MATCH_ENTRY_KIND_ROUND: u8 = 0x01;
+
+const NO_WINNER: u8 = 0xff;
+const VERSION: u8 = 0x11;
+const STRUCTURED_METADATA_VERSION: u8 = 0x11;
+const FRAMED_VERSION: u8 = 0x10;
+const LEGACY_VERSION: u8 = 0x0f;
+
+pub const METADATA_VERSION: u32 = 1;
+
+pub(crate) const FRAME_KIND_INPUT: u8 = 0x00;
+const FRAME_KIND_KEYFRAME: u8 = 0x01;
+const FRAME_KIND_REMOTE_STATE: u8 = 0x02;
+const FRAME_KIND_STATE_HASH: u8 = 0x03;
+const FRAME_KIND_METADATA: u8 = 0x04;
+const FRAME_KIND_TELEMETRY: u8 = 0x05;
+const FRAME_KIND_SIGNATURE: u8 = 0x06;
+const FRAME_KIND_FORFEIT: u8 = 0x07;
+const FRAME_KIND_GOODBYE: u8 = 0x08;
+
+const MAX_FRAME_LENGTH: u32 = 16 * 1024 * 1024;
+
+// How long a player keeps running once the replay is out of inputs, waiting for the battle to end.
+const MAX_FRAMES_WITHOUT_INPUTS: u32 = 60;
+
+// Flush the zstd stream every this many inputs, so a crash loses at most this many ticks.
+const FLUSH_INTERVAL: u32 = 60;
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PlayerMetadata {
+    pub nickname: String,
+    pub rom_title: Option<String>,
+    pub rom_crc32: Option<u32>,
+    pub input_delay: u32,
+    pub public_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    pub version: u32,
+    pub match_type: u16,
+    pub round_number: u8,
+    pub timestamp: u64,
+    pub local_player: PlayerMetadata,
+    pub remote_player: PlayerMetadata,
+
+    // Replays written before metadata was structured carry whatever bytes the frontend passed in here.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub legacy: Option<Vec<u8>>,
+}
 
 pub struct Replay {
-    pub metadata: Vec<u8>,
+    pub metadata: Metadata,
     pub local_player_index: u8,
     pub local_state: mgba::state::State,
+    pub remote_state: Option<mgba::state::State>,
     pub input_pairs: Vec<input::Pair<input::Input>>,
+    pub keyframes: Vec<Keyframe>,
+    pub state_hashes: Vec<StateHash>,
+    pub telemetry: Vec<TelemetrySample>,
+    pub forfeit: Option<Forfeit>,
+    pub goodbyes: Vec<Goodbye>,
+    pub signature: Option<Signature>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Forfeit {
+    pub input_index: u32,
+    pub player_index: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct Goodbye {
+    pub input_index: u32,
+    pub player_index: u8,
+    pub reason: protocol::GoodbyeReason,
+}
+
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub digest: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct Keyframe {
+    pub input_index: u32,
+    pub state: mgba::state::State,
+}
+
+pub struct MatchRound {
+    pub replay: Replay,
+    pub winner: Option<u8>,
+}
+
+pub struct MatchReplay {
+    pub rng_commitment: Vec<u8>,
+    pub rounds: Vec<MatchRound>,
+}
+
+pub struct MatchWriter {
+    writer: Option<Box<dyn WriteSeek + Send>>,
+    round_length_position: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct StateHash {
+    pub input_index: u32,
+    pub hash: u32,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Telemetry {
+    pub tick: u32,
+    pub local_queue_length: u32,
+    pub remote_queue_length: u32,
+    pub tps_adjustment: i32,
+    pub resimulated_ticks: u32,
+    pub remote_lag: i32,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct TelemetrySample {
+    pub input_index: u32,
+    pub telemetry: Telemetry,
+}
+
+pub fn state_hash(state: &mgba::state::State) -> u32 {
+    crc32fast::hash(state.wram())
+}
+
+pub fn load_or_generate_signing_key(
+    path: &std::path::Path,
+) -> anyhow::Result<ed25519_dalek::Keypair> {
+    let secret = match std::fs::read(path) {
+        Ok(buf) => ed25519_dalek::SecretKey::from_bytes(&buf)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut buf = [0u8; ed25519_dalek::SECRET_KEY_LENGTH];
+            rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut buf);
+            // This is the key that vouches for every replay we sign, so nobody else gets to read it.
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options.open(path)?.write_all(&buf)?;
+            log::info!("generated new replay signing key: {}", path.display());
+            ed25519_dalek::SecretKey::from_bytes(&buf)?
+        }
+        Err(e) => {
+            return Err(e.into());
+        }
+    };
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    Ok(ed25519_dalek::Keypair { secret, public })
+}
+
+#[derive(Debug)]
+pub struct Divergence {
+    pub tick: u32,
+    pub reason: String,
+}
+
+pub struct Player {
+    core: mgba::core::Core,
+    hooks: &'static Box<dyn hooks::Hooks + Send + Sync>,
+    ff_state: fastforwarder::State,
+    ended: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    frames_without_inputs: u32,
+    current_tick: u32,
+    frame_callback: Option<Box<dyn FnMut(&[u8]) + Send>>,
+    audio_callback: Option<(f64, Box<dyn FnMut(&[i16]) + Send>)>,
+    samples: Vec<i16>,
+}
+
+impl Player {
+    pub fn new(rom_path: &std::path::Path, replay: &Replay) -> anyhow::Result<Self> {
+        Self::from_perspective(
+            rom_path,
+            replay.local_player_index,
+            replay.input_pairs.clone(),
+            &replay.local_state,
+        )
+    }
+
+    pub fn new_remote(rom_path: &std::path::Path, replay: &Replay) -> anyhow::Result<Self> {
+        let remote_state = match &replay.remote_state {
+            Some(remote_state) => remote_state,
+            None => {
+                anyhow::bail!("replay does not contain the remote player's state");
+            }
+        };
+
+        Self::from_perspective(
+            rom_path,
+            1 - replay.local_player_index,
+            replay
+                .input_pairs
+                .iter()
+                .cloned()
+                .map(|ip| input::Pair {
+                    local: ip.remote,
+                    remote: ip.local,
+                })
+                .collect(),
+            remote_state,
+        )
+    }
+
+    // Starts from the closest keyframe and plays up to the tick, so what's left to play is the rest of the replay.
+    pub fn new_at_tick(
+        rom_path: &std::path::Path,
+        replay: &Replay,
+        tick: u32,
+    ) -> anyhow::Result<Self> {
+        let input_index = replay.input_index_for_tick(tick);
+        let (start_index, state) = replay.keyframe_before(input_index);
+        let mut player = Self::from_perspective(
+            rom_path,
+            replay.local_player_index,
+            replay.input_pairs[start_index..].to_vec(),
+            state,
+        )?;
+
+        let inputs_pairs_left = replay.input_pairs.len() - input_index;
+        while player.inputs_pairs_left() > inputs_pairs_left && !player.is_done() {
+            player.step()?;
+        }
+        log::info!("seeked to tick {}", player.current_tick());
+        Ok(player)
+    }
+
+    pub fn from_perspective(
+        rom_path: &std::path::Path,
+        local_player_index: u8,
+        input_pairs: Vec<input::Pair<input::Input>>,
+        state: &mgba::state::State,
+    ) -> anyhow::Result<Self> {
+        let mut core = mgba::core::Core::new_gba("tango")?;
+        let rom_vf = mgba::vfile::VFile::open(rom_path, mgba::vfile::flags::O_RDONLY)?;
+        core.as_mut().load_rom(rom_vf)?;
+        core.as_mut().reset();
+
+        let hooks = *hooks::HOOKS
+            .get(&core.as_ref().game_title())
+            .ok_or_else(|| anyhow::anyhow!("unsupported game"))?;
+        hooks.prepare_for_fastforward(core.as_mut());
+
+        let ended = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let ff_state = {
+            let ended = ended.clone();
+            fastforwarder::State::new(
+                local_player_index,
+                input_pairs,
+                0,
+                0,
+                Box::new(move || {
+                    ended.store(true, std::sync::atomic::Ordering::Relaxed);
+                }),
+            )
+        };
+        core.set_traps(hooks.fastforwarder_traps(ff_state.clone()));
+        core.as_mut().load_state(state)?;
+        let current_tick = hooks.current_tick(core.as_mut());
+
+        Ok(Self {
+            core,
+            hooks,
+            ff_state,
+            ended,
+            frames_without_inputs: 0,
+            current_tick,
+            frame_callback: None,
+            audio_callback: None,
+            samples: vec![],
+        })
+    }
+
+    /// Sets a callback that receives the RGBA video buffer after every frame.
+    pub fn set_frame_callback(&mut self, f: impl FnMut(&[u8]) + Send + 'static) {
+        if self.core.video_buffer().is_none() {
+            self.core.enable_video_buffer();
+        }
+        self.frame_callback = Some(Box::new(f));
+    }
+
+    /// Sets a callback that receives interleaved stereo samples at `sample_rate` after every frame.
+    pub fn set_audio_callback(&mut self, sample_rate: f64, f: impl FnMut(&[i16]) + Send + 'static) {
+        self.samples = vec![0i16; sample_rate as usize];
+        self.audio_callback = Some((sample_rate, Box::new(f)));
+    }
+
+    pub fn current_tick(&self) -> u32 {
+        self.current_tick
+    }
+
+    // Doesn't depend on whose perspective the player is running from, so it can be compared across perspectives.
+    pub fn shared_state_hash(&mut self) -> u32 {
+        self.hooks.shared_state_hash(self.core.as_mut())
+    }
+
+    pub fn inputs_pairs_left(&self) -> usize {
+        self.ff_state.inputs_pairs_left()
+    }
+
+    pub fn has_battle_ended(&self) -> bool {
+        self.ended.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    // The battle can take a few frames after the last input to actually end, so we give it a little while. A truncated replay never gets there, though.
+    pub fn is_done(&self) -> bool {
+        self.has_battle_ended() || self.frames_without_inputs > MAX_FRAMES_WITHOUT_INPUTS
+    }
+
+    /// Runs a single frame and returns the tick the game is on afterwards.
+    pub fn step(&mut self) -> anyhow::Result<u32> {
+        self.core.as_mut().run_frame();
+        if let Some(e) = self.ff_state.take_error() {
+            return Err(e);
+        }
+        self.current_tick = self.hooks.current_tick(self.core.as_mut());
+        if self.ff_state.inputs_pairs_left() == 0 {
+            self.frames_without_inputs += 1;
+        }
+
+        if let Some(frame_callback) = self.frame_callback.as_mut() {
+            frame_callback(self.core.video_buffer().expect("video buffer"));
+        }
+
+        if let Some((sample_rate, audio_callback)) = self.audio_callback.as_mut() {
+            let clock_rate = self.core.as_ref().frequency();
+            let n = {
+                let mut core = self.core.as_mut();
+                let mut left = core.audio_channel(0);
+                left.set_rates(clock_rate as f64, *sample_rate);
+                let n = left.samples_avail();
+                left.read_samples(&mut self.samples[..(n * 2) as usize], n, true);
+                n
+            };
+            {
+                let mut core = self.core.as_mut();
+                let mut right = core.audio_channel(1);
+                right.set_rates(clock_rate as f64, *sample_rate);
+                right.read_samples(&mut self.samples[1..(n * 2) as usize], n, true);
+            }
+            audio_callback(&self.samples[..(n * 2) as usize]);
+        }
+
+        Ok(self.current_tick)
+    }
+
+    pub fn run_to_end(&mut self) -> anyhow::Result<()> {
+        while !self.is_done() {
+            self.step()?;
+        }
+        if !self.has_battle_ended() {
+            log::warn!("replay ran out of inputs before the battle ended");
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn read_input_pair(
+    mut r: impl std::io::Read,
+    local_player_index: u8,
+) -> std::io::Result<input::Pair<input::Input>> {
+    let local_tick = r.read_u32::<byteorder::LittleEndian>()?;
+    let remote_tick = r.read_u32::<byteorder::LittleEndian>()?;
+
+    let p1_joyflags = r.read_u16::<byteorder::LittleEndian>()?;
+    let p2_joyflags = r.read_u16::<byteorder::LittleEndian>()?;
+
+    let p1_custom_screen_state = r.read_u8()?;
+    let p2_custom_screen_state = r.read_u8()?;
+
+    let mut p1_turn = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+    r.read_exact(&mut p1_turn)?;
+
+    let mut p2_turn = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+    r.read_exact(&mut p2_turn)?;
+
+    let p1_input = input::Input {
+        local_tick,
+        remote_tick,
+        joyflags: p1_joyflags,
+        custom_screen_state: p1_custom_screen_state,
+        turn: p1_turn,
+    };
+
+    let p2_input = input::Input {
+        local_tick,
+        remote_tick: local_tick,
+        joyflags: p2_joyflags,
+        custom_screen_state: p2_custom_screen_state,
+        turn: p2_turn,
+    };
+
+    let (local, remote) = if local_player_index == 0 {
+        (p1_input, p2_input)
+    } else {
+        (p2_input, p1_input)
+    };
+
+    Ok(input::Pair { local, remote })
+}
+
+pub(crate) fn write_input_pair(
+    mut w: impl std::io::Write,
+    local_player_index: u8,
+    ip: &input::Pair<input::Input>,
+) -> std::io::Result<()> {
+    let (p1, p2) = if local_player_index == 0 {
+        (&ip.local, &ip.remote)
+    } else {
+        (&ip.remote, &ip.local)
+    };
+    w.write_u32::<byteorder::LittleEndian>(ip.local.local_tick)?;
+    w.write_u32::<byteorder::LittleEndian>(ip.local.remote_tick)?;
+
+    w.write_u16::<byteorder::LittleEndian>(p1.joyflags)?;
+    w.write_u16::<byteorder::LittleEndian>(p2.joyflags)?;
+
+    w.write_u8(p1.custom_screen_state)?;
+    w.write_u8(p2.custom_screen_state)?;
+
+    w.write_u32::<byteorder::LittleEndian>(p1.turn.len() as u32)?;
+    w.write_all(&p1.turn)?;
+    w.write_u32::<byteorder::LittleEndian>(p2.turn.len() as u32)?;
+    w.write_all(&p2.turn)?;
+    Ok(())
+}
+
+fn read_state(mut r: impl std::io::Read) -> std::io::Result<Vec<u8>> {
+    let mut state = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+    r.read_exact(&mut state)?;
+    Ok(state)
+}
+
+pub(crate) fn write_frame(
+    mut w: impl std::io::Write,
+    kind: u8,
+    payload: &[u8],
+) -> std::io::Result<()> {
+    w.write_u8(kind)?;
+    w.write_u32::<byteorder::LittleEndian>(payload.len() as u32)?;
+    w.write_all(payload)?;
+    w.write_u32::<byteorder::LittleEndian>(crc32fast::hash(payload))?;
+    Ok(())
+}
+
+pub(crate) fn read_frame(mut r: impl std::io::Read) -> std::io::Result<Option<(u8, Vec<u8>)>> {
+    let kind = match r.read_u8() {
+        Ok(kind) => kind,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Ok(None);
+        }
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
+    let len = r.read_u32::<byteorder::LittleEndian>()?;
+    if len > MAX_FRAME_LENGTH {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame too long",
+        ));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)?;
+
+    if r.read_u32::<byteorder::LittleEndian>()? != crc32fast::hash(&payload) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame checksum mismatch",
+        ));
+    }
+
+    Ok(Some((kind, payload)))
 }
 
 impl Replay {
-    pub fn decode(mut r: impl std::io::Read) -> std::io::Result<Self> {
+    pub fn decode(r: impl std::io::Read) -> std::io::Result<Self> {
+        Self::decode_inner(r, false)
+    }
+
+    // Decodes as much of the replay as possible, even if it was never finished or was truncated partway through.
+    // Only inputs from complete frames are returned.
+    pub fn recover(r: impl std::io::Read) -> std::io::Result<Self> {
+        Self::decode_inner(r, true)
+    }
+
+    fn decode_inner(mut r: impl std::io::Read, allow_truncated: bool) -> std::io::Result<Self> {
         let mut header = [0u8; 4];
         r.read_exact(&mut header)?;
         if &header != HEADER {
@@ -32,7 +564,8 @@ impl Replay {
             ));
         }
 
-        if r.read_u8()? != VERSION {
+        let version = r.read_u8()?;
+        if !(LEGACY_VERSION..=VERSION).contains(&version) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "invalid version",
@@ -40,7 +573,7 @@ impl Replay {
         }
 
         let num_inputs = r.read_u32::<byteorder::LittleEndian>()?;
-        if num_inputs == 0 {
+        if num_inputs == 0 && !(allow_truncated && version >= FRAMED_VERSION) {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "replay was not finished",
@@ -48,90 +581,485 @@ impl Replay {
         }
 
         let metadata_len = r.read_u32::<byteorder::LittleEndian>()?;
-        let mut metadata = vec![0u8; metadata_len as usize];
-        r.read_exact(&mut metadata[..])?;
+        let mut legacy_metadata = vec![0u8; metadata_len as usize];
+        r.read_exact(&mut legacy_metadata[..])?;
+
+        let mut metadata = if version < STRUCTURED_METADATA_VERSION {
+            Metadata {
+                legacy: Some(legacy_metadata),
+                ..Default::default()
+            }
+        } else {
+            Metadata::default()
+        };
 
-        let mut zr = zstd::stream::read::Decoder::new(r)?;
+        let mut zr = HashingReader {
+            inner: zstd::stream::read::Decoder::new(r)?,
+            hasher: sha3::Sha3_256::new(),
+        };
 
         let local_player_index = zr.read_u8()?;
 
-        let mut local_state = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
-        zr.read_exact(&mut local_state)?;
-        let local_state = mgba::state::State::from_slice(&local_state);
+        let local_state = mgba::state::State::from_slice(&read_state(&mut zr)?);
 
-        // This is unused, for now.
-        let mut remote_state = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
-        zr.read_exact(&mut remote_state)?;
+        let mut remote_state = match read_state(&mut zr)? {
+            remote_state if remote_state.is_empty() => None,
+            remote_state => Some(mgba::state::State::from_slice(&remote_state)),
+        };
 
         let mut input_pairs = vec![];
+        let mut keyframes = vec![];
+        let mut state_hashes = vec![];
+        let mut telemetry = vec![];
+        let mut forfeit = None;
+        let mut goodbyes = vec![];
+        let mut signature = None;
+
+        if version < FRAMED_VERSION {
+            for _ in 0..num_inputs {
+                input_pairs.push(read_input_pair(&mut zr, local_player_index)?);
+            }
+
+            // Keyframes only ever shipped in framed replays, so legacy ones always start from the initial state.
+        } else {
+            loop {
+                let hasher = zr.hasher.clone();
+                let (kind, payload) = match read_frame(&mut zr) {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => {
+                        break;
+                    }
+                    Err(e) => {
+                        if allow_truncated {
+                            log::warn!(
+                                "replay truncated after {} inputs: {}",
+                                input_pairs.len(),
+                                e
+                            );
+                            break;
+                        }
+                        return Err(e);
+                    }
+                };
+
+                let mut payload = payload.as_slice();
+                match kind {
+                    FRAME_KIND_INPUT => {
+                        input_pairs.push(read_input_pair(&mut payload, local_player_index)?);
+                    }
+                    FRAME_KIND_KEYFRAME => {
+                        let input_index = payload.read_u32::<byteorder::LittleEndian>()?;
+                        keyframes.push(Keyframe {
+                            input_index,
+                            state: mgba::state::State::from_slice(payload),
+                        });
+                    }
+                    FRAME_KIND_REMOTE_STATE => {
+                        remote_state = Some(mgba::state::State::from_slice(payload));
+                    }
+                    FRAME_KIND_METADATA => {
+                        metadata = serde_json::from_slice(payload)?;
+                    }
+                    FRAME_KIND_STATE_HASH => {
+                        state_hashes.push(StateHash {
+                            input_index: payload.read_u32::<byteorder::LittleEndian>()?,
+                            hash: payload.read_u32::<byteorder::LittleEndian>()?,
+                        });
+                    }
+                    FRAME_KIND_TELEMETRY => {
+                        telemetry.push(TelemetrySample {
+                            input_index: payload.read_u32::<byteorder::LittleEndian>()?,
+                            telemetry: Telemetry {
+                                tick: payload.read_u32::<byteorder::LittleEndian>()?,
+                                local_queue_length: payload
+                                    .read_u32::<byteorder::LittleEndian>()?,
+                                remote_queue_length: payload
+                                    .read_u32::<byteorder::LittleEndian>()?,
+                                tps_adjustment: payload.read_i32::<byteorder::LittleEndian>()?,
+                                resimulated_ticks: payload.read_u32::<byteorder::LittleEndian>()?,
+                                remote_lag: payload.read_i32::<byteorder::LittleEndian>()?,
+                            },
+                        });
+                    }
+                    FRAME_KIND_FORFEIT => {
+                        forfeit = Some(Forfeit {
+                            input_index: payload.read_u32::<byteorder::LittleEndian>()?,
+                            player_index: payload.read_u8()?,
+                        });
+                    }
+                    FRAME_KIND_GOODBYE => {
+                        goodbyes.push(Goodbye {
+                            input_index: payload.read_u32::<byteorder::LittleEndian>()?,
+                            player_index: payload.read_u8()?,
+                            reason: serde_json::from_slice(payload)?,
+                        });
+                    }
+                    FRAME_KIND_SIGNATURE => {
+                        signature = Some(Signature {
+                            digest: hasher.finalize().to_vec(),
+                            signature: payload.to_vec(),
+                        });
+                        continue;
+                    }
+                    _ => {
+                        log::warn!("skipping unknown frame kind: {}", kind);
+                    }
+                }
+
+                if signature.take().is_some() {
+                    log::warn!("replay has frames after its signature, ignoring signature");
+                }
+            }
+
+            if !allow_truncated && input_pairs.len() != num_inputs as usize {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "replay input count mismatch",
+                ));
+            }
+        }
+
+        Ok(Self {
+            metadata,
+            local_player_index,
+            local_state,
+            remote_state,
+            input_pairs,
+            keyframes,
+            state_hashes,
+            telemetry,
+            forfeit,
+            goodbyes,
+            signature,
+        })
+    }
+
+    fn tick_for_input_index(&self, input_index: usize) -> u32 {
+        match self.input_pairs.get(input_index) {
+            Some(ip) => ip.local.local_tick,
+            None => self
+                .input_pairs
+                .last()
+                .map_or(0, |ip| ip.local.local_tick + 1),
+        }
+    }
+
+    pub fn input_index_for_tick(&self, tick: u32) -> usize {
+        self.input_pairs
+            .iter()
+            .position(|ip| ip.local.local_tick >= tick)
+            .unwrap_or(self.input_pairs.len())
+    }
+
+    // The last keyframe at or before the input index, as the input index it starts at and its state.
+    fn keyframe_before(&self, input_index: usize) -> (usize, &mgba::state::State) {
+        match self
+            .keyframes
+            .iter()
+            .rev()
+            .find(|kf| kf.input_index as usize <= input_index)
+        {
+            Some(kf) => (kf.input_index as usize, &kf.state),
+            None => (0, &self.local_state),
+        }
+    }
+
+    pub fn verify_signature(&self) -> anyhow::Result<ed25519_dalek::PublicKey> {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => {
+                anyhow::bail!("replay is not signed");
+            }
+        };
+
+        let public_key = match &self.metadata.local_player.public_key {
+            Some(public_key) => ed25519_dalek::PublicKey::from_bytes(&hex::decode(public_key)?)?,
+            None => {
+                anyhow::bail!("replay metadata does not contain a public key");
+            }
+        };
+
+        public_key.verify(
+            &signature.digest,
+            &ed25519_dalek::Signature::try_from(&signature.signature[..])?,
+        )?;
+        Ok(public_key)
+    }
+
+    pub fn verify_state_hashes(
+        &self,
+        ff: &mut fastforwarder::Fastforwarder,
+    ) -> anyhow::Result<Option<Divergence>> {
+        let mut state = self.local_state.clone();
+        let mut input_index = 0;
+
+        for sh in &self.state_hashes {
+            let next_input_index = sh.input_index as usize;
+            if next_input_index < input_index || next_input_index > self.input_pairs.len() {
+                anyhow::bail!("state hash has invalid input index: {}", sh.input_index);
+            }
+
+            let input_pairs = &self.input_pairs[input_index..next_input_index];
+            if let Some(last_input_pair) = input_pairs.last() {
+                let (committed_state, _, _) =
+                    ff.fastforward(&state, input_pairs, last_input_pair.remote.clone(), &[])?;
+                state = committed_state;
+            }
+            input_index = next_input_index;
+
+            let hash = state_hash(&state);
+            if hash != sh.hash {
+                return Ok(Some(Divergence {
+                    tick: self.tick_for_input_index(input_index),
+                    reason: format!(
+                        "state hash mismatch: expected {:08x}, got {:08x}",
+                        sh.hash, hash
+                    ),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Re-simulates the round from both players' perspectives in lockstep, returning the first tick where they disagree.
+    pub fn verify_perspectives(
+        &self,
+        rom_path: &std::path::Path,
+    ) -> anyhow::Result<Option<Divergence>> {
+        let mut local = Player::new(rom_path, self)?;
+        let mut remote = Player::new_remote(rom_path, self)?;
+
+        // Truncated replays never reach the end of the battle, so stop once either side runs out of inputs too.
+        let is_done = |player: &Player| player.is_done() || player.inputs_pairs_left() == 0;
+
+        let mut last_tick = 0;
+        while !is_done(&local) || !is_done(&remote) {
+            let local_tick = match local.step() {
+                Ok(tick) => tick,
+                Err(e) => {
+                    return Ok(Some(Divergence {
+                        tick: last_tick,
+                        reason: format!("local perspective failed: {}", e),
+                    }));
+                }
+            };
+
+            let remote_tick = match remote.step() {
+                Ok(tick) => tick,
+                Err(e) => {
+                    return Ok(Some(Divergence {
+                        tick: last_tick,
+                        reason: format!("remote perspective failed: {}", e),
+                    }));
+                }
+            };
 
-        for _ in 0..num_inputs {
-            let local_tick = zr.read_u32::<byteorder::LittleEndian>()?;
-            let remote_tick = zr.read_u32::<byteorder::LittleEndian>()?;
+            if local_tick != remote_tick {
+                return Ok(Some(Divergence {
+                    tick: std::cmp::min(local_tick, remote_tick),
+                    reason: format!(
+                        "tick mismatch: local = {}, remote = {}",
+                        local_tick, remote_tick
+                    ),
+                }));
+            }
 
-            let p1_joyflags = zr.read_u16::<byteorder::LittleEndian>()?;
-            let p2_joyflags = zr.read_u16::<byteorder::LittleEndian>()?;
+            let local_hash = local.shared_state_hash();
+            let remote_hash = remote.shared_state_hash();
+            if local_hash != remote_hash {
+                return Ok(Some(Divergence {
+                    tick: local_tick,
+                    reason: format!(
+                        "state hash mismatch: local = {:08x}, remote = {:08x}",
+                        local_hash, remote_hash
+                    ),
+                }));
+            }
 
-            let p1_custom_screen_state = zr.read_u8()?;
-            let p2_custom_screen_state = zr.read_u8()?;
+            if is_done(&local) != is_done(&remote) {
+                return Ok(Some(Divergence {
+                    tick: local_tick,
+                    reason: format!(
+                        "battle ended early in the {} perspective",
+                        if is_done(&local) { "local" } else { "remote" }
+                    ),
+                }));
+            }
 
-            let mut p1_turn = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
-            zr.read_exact(&mut p1_turn)?;
+            last_tick = local_tick;
+        }
+
+        Ok(None)
+    }
+}
+
+impl MatchReplay {
+    pub fn decode(r: impl std::io::Read) -> std::io::Result<Self> {
+        Self::decode_inner(r, false)
+    }
+
+    pub fn recover(r: impl std::io::Read) -> std::io::Result<Self> {
+        Self::decode_inner(r, true)
+    }
+
+    fn decode_inner(mut r: impl std::io::Read, allow_truncated: bool) -> std::io::Result<Self> {
+        let mut header = [0u8; 4];
+        r.read_exact(&mut header)?;
+
+        // Bare round replays are treated as a match with a single round.
+        if &header == HEADER {
+            return Ok(Self {
+                rng_commitment: vec![],
+                rounds: vec![MatchRound {
+                    replay: Replay::decode_inner((&header[..]).chain(r), allow_truncated)?,
+                    winner: None,
+                }],
+            });
+        }
+
+        if &header != MATCH_HEADER {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "invalid header",
+            ));
+        }
+
+        if r.read_u8()? != MATCH_VERSION {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "invalid version",
+            ));
+        }
 
-            let mut p2_turn = vec![0u8; zr.read_u32::<byteorder::LittleEndian>()? as usize];
-            zr.read_exact(&mut p2_turn)?;
+        let mut rng_commitment = vec![0u8; r.read_u32::<byteorder::LittleEndian>()? as usize];
+        r.read_exact(&mut rng_commitment)?;
 
-            let p1_input = input::Input {
-                local_tick,
-                remote_tick,
-                joyflags: p1_joyflags,
-                custom_screen_state: p1_custom_screen_state,
-                turn: p1_turn,
+        let mut rounds = vec![];
+        loop {
+            let kind = match r.read_u8() {
+                Ok(kind) => kind,
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    break;
+                }
+                Err(e) => {
+                    return Err(e);
+                }
             };
 
-            let p2_input = input::Input {
-                local_tick,
-                remote_tick: local_tick,
-                joyflags: p2_joyflags,
-                custom_screen_state: p2_custom_screen_state,
-                turn: p2_turn,
+            if kind != MATCH_ENTRY_KIND_ROUND {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "invalid match entry",
+                ));
+            }
+
+            // A round that was never ended has no length written, so it runs until the end of the file.
+            let len = match r.read_u64::<byteorder::LittleEndian>()? {
+                0 => u64::MAX,
+                len => len,
             };
+            let mut buf = vec![];
+            (&mut r).take(len).read_to_end(&mut buf)?;
+            let replay = Replay::decode_inner(buf.as_slice(), allow_truncated)?;
 
-            let (local, remote) = if local_player_index == 0 {
-                (p1_input, p2_input)
-            } else {
-                (p2_input, p1_input)
+            let winner = match r.read_u8() {
+                Ok(NO_WINNER) => None,
+                Ok(winner) => Some(winner),
+                Err(e) if allow_truncated && e.kind() == std::io::ErrorKind::UnexpectedEof => None,
+                Err(e) => {
+                    return Err(e);
+                }
             };
 
-            input_pairs.push(input::Pair { local, remote });
+            rounds.push(MatchRound { replay, winner });
         }
 
         Ok(Self {
-            metadata,
-            local_player_index,
-            local_state,
-            input_pairs,
+            rng_commitment,
+            rounds,
         })
     }
+
+    pub fn rounds(&self) -> impl Iterator<Item = &MatchRound> {
+        self.rounds.iter()
+    }
+}
+
+impl MatchWriter {
+    pub fn new(
+        mut writer: Box<dyn WriteSeek + Send>,
+        rng_commitment: &[u8],
+    ) -> std::io::Result<Self> {
+        writer.write_all(MATCH_HEADER)?;
+        writer.write_u8(MATCH_VERSION)?;
+        writer.write_u32::<byteorder::LittleEndian>(rng_commitment.len() as u32)?;
+        writer.write_all(rng_commitment)?;
+        writer.flush()?;
+        Ok(MatchWriter {
+            writer: Some(writer),
+            round_length_position: 0,
+        })
+    }
+
+    pub fn begin_round(&mut self) -> std::io::Result<Box<dyn WriteSeek + Send>> {
+        let mut writer = self.writer.take().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Other, "previous round was not ended")
+        })?;
+        writer.write_u8(MATCH_ENTRY_KIND_ROUND)?;
+        self.round_length_position = writer.stream_position()?;
+        writer.write_u64::<byteorder::LittleEndian>(0)?;
+        Ok(writer)
+    }
+
+    pub fn end_round(
+        &mut self,
+        mut writer: Box<dyn WriteSeek + Send>,
+        winner: Option<u8>,
+    ) -> std::io::Result<()> {
+        let end_position = writer.stream_position()?;
+        writer.seek(std::io::SeekFrom::Start(self.round_length_position))?;
+        writer
+            .write_u64::<byteorder::LittleEndian>(end_position - self.round_length_position - 8)?;
+        writer.seek(std::io::SeekFrom::Start(end_position))?;
+        writer.write_u8(winner.unwrap_or(NO_WINNER))?;
+        writer.flush()?;
+        self.writer = Some(writer);
+        Ok(())
+    }
 }
 
 impl Writer {
     pub fn new(
         mut writer: Box<dyn WriteSeek + Send>,
-        metadata: &[u8],
         local_player_index: u8,
+        keyframe_interval: u32,
+        state_hash_interval: u32,
+        record_telemetry: bool,
+        signing_key: Option<std::sync::Arc<ed25519_dalek::Keypair>>,
     ) -> std::io::Result<Self> {
+        let start_position = writer.stream_position()?;
         writer.write_all(HEADER)?;
         writer.write_u8(VERSION)?;
         writer.write_u32::<byteorder::LittleEndian>(0)?;
-        writer.write_u32::<byteorder::LittleEndian>(metadata.len() as u32)?;
-        writer.write_all(metadata)?;
-        let mut encoder = zstd::Encoder::new(writer, 3)?;
+        // Metadata is written as a frame once it is known, so this section is left empty.
+        writer.write_u32::<byteorder::LittleEndian>(0)?;
+        let mut encoder = HashingWriter {
+            inner: zstd::Encoder::new(writer, 3)?,
+            hasher: sha3::Sha3_256::new(),
+        };
         encoder.write_u8(local_player_index)?;
         encoder.flush()?;
         Ok(Writer {
             encoder: Some(encoder),
+            start_position,
             num_inputs: 0,
+            keyframe_interval,
+            last_keyframe_input_index: 0,
+            state_hash_interval,
+            last_state_hash_input_index: 0,
+            record_telemetry,
+            signing_key,
         })
     }
 
@@ -156,62 +1084,132 @@ impl Writer {
         Ok(())
     }
 
+    pub fn write_metadata(&mut self, metadata: &Metadata) -> std::io::Result<()> {
+        self.write_frame(FRAME_KIND_METADATA, &serde_json::to_vec(metadata)?)?;
+        self.encoder.as_mut().unwrap().flush()?;
+        Ok(())
+    }
+
+    pub fn write_remote_state(&mut self, state: &mgba::state::State) -> std::io::Result<()> {
+        self.write_frame(FRAME_KIND_REMOTE_STATE, state.as_slice())?;
+        self.encoder.as_mut().unwrap().flush()?;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, kind: u8, payload: &[u8]) -> std::io::Result<()> {
+        write_frame(self.encoder.as_mut().unwrap(), kind, payload)
+    }
+
+    pub fn write_keyframe(&mut self, state: &mgba::state::State) -> std::io::Result<()> {
+        let mut payload = Vec::with_capacity(4 + state.as_slice().len());
+        payload.write_u32::<byteorder::LittleEndian>(self.num_inputs)?;
+        payload.write_all(state.as_slice())?;
+        self.write_frame(FRAME_KIND_KEYFRAME, &payload)?;
+        self.encoder.as_mut().unwrap().flush()?;
+        self.last_keyframe_input_index = self.num_inputs;
+        Ok(())
+    }
+
+    pub fn offer_keyframe(&mut self, state: &mgba::state::State) -> std::io::Result<()> {
+        if self.keyframe_interval == 0 {
+            return Ok(());
+        }
+
+        if self.num_inputs < self.last_keyframe_input_index + self.keyframe_interval {
+            return Ok(());
+        }
+
+        self.write_keyframe(state)
+    }
+
+    pub fn write_state_hash(&mut self, hash: u32) -> std::io::Result<()> {
+        let mut payload = Vec::with_capacity(8);
+        payload.write_u32::<byteorder::LittleEndian>(self.num_inputs)?;
+        payload.write_u32::<byteorder::LittleEndian>(hash)?;
+        self.write_frame(FRAME_KIND_STATE_HASH, &payload)?;
+        self.last_state_hash_input_index = self.num_inputs;
+        Ok(())
+    }
+
+    pub fn offer_state_hash(&mut self, state: &mgba::state::State) -> std::io::Result<()> {
+        if self.state_hash_interval == 0 {
+            return Ok(());
+        }
+
+        if self.num_inputs < self.last_state_hash_input_index + self.state_hash_interval {
+            return Ok(());
+        }
+
+        self.write_state_hash(state_hash(state))
+    }
+
+    pub fn write_telemetry(&mut self, telemetry: &Telemetry) -> std::io::Result<()> {
+        let mut payload = Vec::with_capacity(28);
+        payload.write_u32::<byteorder::LittleEndian>(self.num_inputs)?;
+        payload.write_u32::<byteorder::LittleEndian>(telemetry.tick)?;
+        payload.write_u32::<byteorder::LittleEndian>(telemetry.local_queue_length)?;
+        payload.write_u32::<byteorder::LittleEndian>(telemetry.remote_queue_length)?;
+        payload.write_i32::<byteorder::LittleEndian>(telemetry.tps_adjustment)?;
+        payload.write_u32::<byteorder::LittleEndian>(telemetry.resimulated_ticks)?;
+        payload.write_i32::<byteorder::LittleEndian>(telemetry.remote_lag)?;
+        self.write_frame(FRAME_KIND_TELEMETRY, &payload)
+    }
+
+    pub fn offer_telemetry(&mut self, telemetry: &Telemetry) -> std::io::Result<()> {
+        if !self.record_telemetry {
+            return Ok(());
+        }
+
+        self.write_telemetry(telemetry)
+    }
+
+    pub fn write_forfeit(&mut self, player_index: u8) -> std::io::Result<()> {
+        let mut payload = Vec::with_capacity(5);
+        payload.write_u32::<byteorder::LittleEndian>(self.num_inputs)?;
+        payload.write_u8(player_index)?;
+        self.write_frame(FRAME_KIND_FORFEIT, &payload)
+    }
+
+    pub fn write_goodbye(
+        &mut self,
+        player_index: u8,
+        reason: &protocol::GoodbyeReason,
+    ) -> std::io::Result<()> {
+        let mut payload = Vec::with_capacity(5);
+        payload.write_u32::<byteorder::LittleEndian>(self.num_inputs)?;
+        payload.write_u8(player_index)?;
+        payload.write_all(&serde_json::to_vec(reason)?)?;
+        self.write_frame(FRAME_KIND_GOODBYE, &payload)
+    }
+
     pub fn write_input(
         &mut self,
         local_player_index: u8,
         ip: &input::Pair<input::Input>,
     ) -> std::io::Result<()> {
-        let (p1, p2) = if local_player_index == 0 {
-            (&ip.local, &ip.remote)
-        } else {
-            (&ip.remote, &ip.local)
-        };
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u32::<byteorder::LittleEndian>(ip.local.local_tick)?;
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u32::<byteorder::LittleEndian>(ip.local.remote_tick)?;
-
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u16::<byteorder::LittleEndian>(p1.joyflags)?;
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u16::<byteorder::LittleEndian>(p2.joyflags)?;
-
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u8(p1.custom_screen_state)?;
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u8(p2.custom_screen_state)?;
-
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u32::<byteorder::LittleEndian>(p1.turn.len() as u32)?;
-        self.encoder.as_mut().unwrap().write_all(&p1.turn)?;
-        self.encoder
-            .as_mut()
-            .unwrap()
-            .write_u32::<byteorder::LittleEndian>(p2.turn.len() as u32)?;
-        self.encoder.as_mut().unwrap().write_all(&p2.turn)?;
+        let mut payload = vec![];
+        write_input_pair(&mut payload, local_player_index, ip)?;
+        self.write_frame(FRAME_KIND_INPUT, &payload)?;
 
         self.num_inputs += 1;
+        if self.num_inputs % FLUSH_INTERVAL == 0 {
+            self.encoder.as_mut().unwrap().flush()?;
+        }
         Ok(())
     }
 
     pub fn finish(mut self) -> std::io::Result<Box<dyn WriteSeek + Send>> {
-        let mut w = self.encoder.take().unwrap().finish()?;
-        w.seek(std::io::SeekFrom::Start((HEADER.len() + 1) as u64))?;
+        if let Some(signing_key) = self.signing_key.clone() {
+            let digest = self.encoder.as_ref().unwrap().hasher.clone().finalize();
+            self.write_frame(FRAME_KIND_SIGNATURE, &signing_key.sign(&digest).to_bytes())?;
+        }
+        let mut w = self.encoder.take().unwrap().inner.finish()?;
+        let end_position = w.stream_position()?;
+        w.seek(std::io::SeekFrom::Start(
+            self.start_position + (HEADER.len() + 1) as u64,
+        ))?;
         w.write_u32::<byteorder::LittleEndian>(self.num_inputs)?;
+        w.seek(std::io::SeekFrom::Start(end_position))?;
         Ok(w)
     }
 }
@@ -220,7 +1218,315 @@ impl Drop for Writer {
     fn drop(&mut self) {
         if let Some(encoder) = self.encoder.take() {
             log::info!("writer was not finished before drop, this replay will be incomplete!");
-            encoder.finish().expect("finish");
+            encoder.inner.finish().expect("finish");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Seek;
+
+    // Header, version, input count and empty metadata section, all uncompressed.
+    const HEADER_LENGTH: usize = 4 + 1 + 4 + 4;
+
+    fn test_input_pair(i: u32) -> input::Pair<input::Input> {
+        input::Pair {
+            local: input::Input {
+                local_tick: i,
+                remote_tick: i.saturating_sub(2),
+                joyflags: 0xfc00 | i as u16,
+                custom_screen_state: (i % 2) as u8,
+                turn: if i % 5 == 0 {
+                    vec![i as u8; 0x100]
+                } else {
+                    vec![]
+                },
+            },
+            remote: input::Input {
+                local_tick: i,
+                remote_tick: i,
+                joyflags: 0xfc00 | (i * 3) as u16,
+                custom_screen_state: 0,
+                turn: vec![],
+            },
+        }
+    }
+
+    // Writer takes ownership of what it writes to, so this lets us get at the bytes afterwards.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<parking_lot::Mutex<std::io::Cursor<Vec<u8>>>>);
+
+    impl std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for SharedBuffer {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.0.lock().seek(pos)
+        }
+    }
+
+    fn write_test_replay(
+        num_inputs: u32,
+        signing_key: Option<std::sync::Arc<ed25519_dalek::Keypair>>,
+        metadata: &Metadata,
+    ) -> Vec<u8> {
+        let buf = SharedBuffer::default();
+        let mut writer = Writer::new(Box::new(buf.clone()), 1, 0, 0, false, signing_key).unwrap();
+        writer.write_state(&mgba::state::State::default()).unwrap();
+        writer.write_state_placeholder().unwrap();
+        writer.write_metadata(metadata).unwrap();
+        for i in 0..num_inputs {
+            writer.write_input(1, &test_input_pair(i)).unwrap();
+        }
+        writer.finish().unwrap();
+        let buf = buf.0.lock().get_ref().clone();
+        buf
+    }
+
+    fn split_test_replay(buf: &[u8]) -> (&[u8], Vec<u8>) {
+        let (header, body) = buf.split_at(HEADER_LENGTH);
+        (header, zstd::stream::decode_all(body).unwrap())
+    }
+
+    fn join_test_replay(header: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut buf = header.to_vec();
+        buf.extend(zstd::stream::encode_all(body, 3).unwrap());
+        buf
+    }
+
+    fn assert_input_pairs_eq(got: &[input::Pair<input::Input>], num_inputs: u32) {
+        assert_eq!(got.len(), num_inputs as usize);
+        for (i, ip) in got.iter().enumerate() {
+            let expected = test_input_pair(i as u32);
+            assert_eq!(ip.local.local_tick, expected.local.local_tick);
+            assert_eq!(ip.local.remote_tick, expected.local.remote_tick);
+            assert_eq!(ip.local.joyflags, expected.local.joyflags);
+            assert_eq!(
+                ip.local.custom_screen_state,
+                expected.local.custom_screen_state
+            );
+            assert_eq!(ip.local.turn, expected.local.turn);
+            assert_eq!(ip.remote.local_tick, expected.remote.local_tick);
+            assert_eq!(ip.remote.joyflags, expected.remote.joyflags);
+            assert_eq!(
+                ip.remote.custom_screen_state,
+                expected.remote.custom_screen_state
+            );
+            assert_eq!(ip.remote.turn, expected.remote.turn);
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buf = vec![];
+        write_frame(&mut buf, FRAME_KIND_INPUT, b"hello").unwrap();
+        write_frame(&mut buf, FRAME_KIND_STATE_HASH, b"").unwrap();
+
+        let mut r = buf.as_slice();
+        assert_eq!(
+            read_frame(&mut r).unwrap(),
+            Some((FRAME_KIND_INPUT, b"hello".to_vec()))
+        );
+        assert_eq!(
+            read_frame(&mut r).unwrap(),
+            Some((FRAME_KIND_STATE_HASH, vec![]))
+        );
+        assert_eq!(read_frame(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn test_replay_roundtrip() {
+        let buf = write_test_replay(30, None, &Metadata::default());
+        let replay = Replay::decode(buf.as_slice()).unwrap();
+        assert_eq!(replay.local_player_index, 1);
+        assert!(replay.remote_state.is_none());
+        assert_input_pairs_eq(&replay.input_pairs, 30);
+    }
+
+    #[test]
+    fn test_unfinished_replay_is_rejected_but_recoverable() {
+        let buf = write_test_replay(30, None, &Metadata::default());
+        let (header, body) = split_test_replay(&buf);
+        let mut header = header.to_vec();
+        header[5..9].copy_from_slice(&0u32.to_le_bytes());
+        let buf = join_test_replay(&header, &body);
+
+        assert!(Replay::decode(buf.as_slice()).is_err());
+        assert_input_pairs_eq(&Replay::recover(buf.as_slice()).unwrap().input_pairs, 30);
+    }
+
+    #[test]
+    fn test_recover_truncated_replay() {
+        const NUM_INPUTS: u32 = 12;
+        let buf = write_test_replay(NUM_INPUTS, None, &Metadata::default());
+        let (header, body) = split_test_replay(&buf);
+
+        // Find where each input frame ends, so we know how many should survive being cut off at any given byte.
+        let mut r = &body[1..];
+        read_state(&mut r).unwrap();
+        read_state(&mut r).unwrap();
+        let frames_start = body.len() - r.len();
+        let mut input_frame_ends = vec![];
+        while let Some((kind, _)) = read_frame(&mut r).unwrap() {
+            if kind == FRAME_KIND_INPUT {
+                input_frame_ends.push(body.len() - r.len());
+            }
+        }
+        assert_eq!(input_frame_ends.len(), NUM_INPUTS as usize);
+
+        for end in frames_start..=body.len() {
+            let replay = Replay::recover(join_test_replay(header, &body[..end]).as_slice())
+                .unwrap_or_else(|e| panic!("failed to recover replay cut off at {}: {}", end, e));
+            let complete = input_frame_ends.iter().filter(|&&e| e <= end).count() as u32;
+            assert_input_pairs_eq(&replay.input_pairs, complete);
+        }
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let buf = write_test_replay(10, None, &Metadata::default());
+        let (header, mut body) = split_test_replay(&buf);
+
+        // The last byte before an input frame's checksum is part of its payload.
+        let mut r = &body[1..];
+        read_state(&mut r).unwrap();
+        read_state(&mut r).unwrap();
+        let mut corrupt_at = None;
+        let mut inputs_before = 0;
+        while let Some((kind, _)) = read_frame(&mut r).unwrap() {
+            if kind == FRAME_KIND_INPUT {
+                if inputs_before == 5 {
+                    corrupt_at = Some(body.len() - r.len() - 5);
+                    break;
+                }
+                inputs_before += 1;
+            }
+        }
+        body[corrupt_at.unwrap()] ^= 0xff;
+        let buf = join_test_replay(header, &body);
+
+        let err = match Replay::decode(buf.as_slice()) {
+            Ok(_) => panic!("corrupted replay decoded"),
+            Err(e) => e,
+        };
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_input_pairs_eq(&Replay::recover(buf.as_slice()).unwrap().input_pairs, 5);
+    }
+
+    #[test]
+    fn test_legacy_replay_decodes() {
+        let state = mgba::state::State::default();
+        let mut body = vec![];
+        body.write_u8(0).unwrap();
+        body.write_u32::<byteorder::LittleEndian>(state.as_slice().len() as u32)
+            .unwrap();
+        body.write_all(state.as_slice()).unwrap();
+        body.write_u32::<byteorder::LittleEndian>(0).unwrap();
+        for i in 0..3 {
+            write_input_pair(&mut body, 0, &test_input_pair(i)).unwrap();
         }
+
+        let mut buf = vec![];
+        buf.write_all(HEADER).unwrap();
+        buf.write_u8(LEGACY_VERSION).unwrap();
+        buf.write_u32::<byteorder::LittleEndian>(3).unwrap();
+        buf.write_u32::<byteorder::LittleEndian>(4).unwrap();
+        buf.write_all(b"meta").unwrap();
+        buf.extend(zstd::stream::encode_all(body.as_slice(), 3).unwrap());
+
+        let replay = Replay::decode(buf.as_slice()).unwrap();
+        assert_eq!(replay.local_player_index, 0);
+        assert_eq!(replay.metadata.legacy, Some(b"meta".to_vec()));
+        assert_eq!(replay.metadata.version, 0);
+        assert!(replay.keyframes.is_empty());
+        assert_input_pairs_eq(&replay.input_pairs, 3);
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let metadata = Metadata {
+            version: METADATA_VERSION,
+            match_type: 1,
+            round_number: 2,
+            timestamp: 1234,
+            local_player: PlayerMetadata {
+                nickname: "local".to_string(),
+                rom_title: Some("MEGAMAN6_FXX".to_string()),
+                rom_crc32: Some(0xdeadbeef),
+                input_delay: 3,
+                public_key: None,
+            },
+            remote_player: PlayerMetadata {
+                nickname: "remote".to_string(),
+                rom_title: Some("MEGAMAN6_GXX".to_string()),
+                rom_crc32: Some(0xcafebabe),
+                input_delay: 4,
+                public_key: None,
+            },
+            legacy: None,
+        };
+        let buf = write_test_replay(1, None, &metadata);
+        let decoded = Replay::decode(buf.as_slice()).unwrap().metadata;
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&metadata).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_signature() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7u8; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let metadata = Metadata {
+            local_player: PlayerMetadata {
+                public_key: Some(hex::encode(public.as_bytes())),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let buf = write_test_replay(
+            10,
+            Some(std::sync::Arc::new(ed25519_dalek::Keypair {
+                secret,
+                public,
+            })),
+            &metadata,
+        );
+
+        let replay = Replay::decode(buf.as_slice()).unwrap();
+        assert_eq!(replay.verify_signature().unwrap(), public);
+
+        // States aren't framed, so a flipped byte in one still decodes and only the signature can catch it.
+        let (header, mut body) = split_test_replay(&buf);
+        body[1 + 4 + 0x100] ^= 0xff;
+        let replay = Replay::decode(join_test_replay(header, &body).as_slice()).unwrap();
+        assert!(replay.verify_signature().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signing_key_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("tango-test-signing-key-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let generated = load_or_generate_signing_key(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        let loaded = load_or_generate_signing_key(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(generated.public, loaded.public);
     }
 }
@@ -0,0 +1,207 @@
+use crate::{input, replay};
+use byteorder::WriteBytesExt;
+use subtle::ConstantTimeEq;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+
+// The spectator stream reuses the replay frame format, so these must not collide with the replay frame kinds.
+const FRAME_KIND_ROUND_START: u8 = 0x80;
+const FRAME_KIND_ROUND_END: u8 = 0x81;
+
+// A spectator that falls this many frames behind is disconnected rather than buffered indefinitely: that's about 10 seconds of inputs.
+const MAX_PENDING_FRAMES: usize = 600;
+
+// Spectators must send the token right after connecting.
+const AUTH_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_TOKEN_LENGTH: u32 = 1024;
+
+pub enum Event {
+    RoundStarted {
+        local_player_index: u8,
+        state: mgba::state::State,
+    },
+    Input(input::Pair<input::Input>),
+    RoundEnded,
+}
+
+struct Inner {
+    local_player_index: u8,
+    // Everything sent since the current round started, so spectators that join mid-round can catch up.
+    backlog: Vec<u8>,
+    spectators: Vec<tokio::sync::mpsc::Sender<Vec<u8>>>,
+}
+
+impl Inner {
+    fn send_frame(&mut self, kind: u8, payload: &[u8]) {
+        let mut buf = vec![];
+        replay::write_frame(&mut buf, kind, payload).expect("write frame");
+        self.backlog.extend_from_slice(&buf);
+        self.spectators.retain(|tx| match tx.try_send(buf.clone()) {
+            Ok(()) => true,
+            Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+                log::warn!("spectator fell too far behind, disconnecting");
+                false
+            }
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => false,
+        });
+    }
+}
+
+#[derive(Clone)]
+pub struct Broadcaster {
+    inner: std::sync::Arc<parking_lot::Mutex<Inner>>,
+    token: std::sync::Arc<String>,
+}
+
+// Sent by the spectator before anything else: only those that know the match's spectator token get to watch.
+pub fn write_token(mut w: impl std::io::Write, token: &str) -> std::io::Result<()> {
+    w.write_u32::<byteorder::LittleEndian>(token.len() as u32)?;
+    w.write_all(token.as_bytes())?;
+    w.flush()
+}
+
+async fn read_token(stream: &mut tokio::net::TcpStream) -> anyhow::Result<Vec<u8>> {
+    let len = stream.read_u32_le().await?;
+    if len > MAX_TOKEN_LENGTH {
+        anyhow::bail!("token too long: {} bytes", len);
+    }
+    let mut token = vec![0u8; len as usize];
+    stream.read_exact(&mut token).await?;
+    Ok(token)
+}
+
+impl Broadcaster {
+    pub fn new(token: String) -> Self {
+        Broadcaster {
+            inner: std::sync::Arc::new(parking_lot::Mutex::new(Inner {
+                local_player_index: 0,
+                backlog: vec![],
+                spectators: vec![],
+            })),
+            token: std::sync::Arc::new(token),
+        }
+    }
+
+    pub async fn listen(&self, addr: &str) -> anyhow::Result<()> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        log::info!("listening for spectators on {}", listener.local_addr()?);
+        loop {
+            let (mut stream, peer_addr) = listener.accept().await?;
+            log::info!("spectator connected: {}", peer_addr);
+
+            let inner = self.inner.clone();
+            let token = self.token.clone();
+            tokio::task::spawn(async move {
+                match tokio::time::timeout(AUTH_TIMEOUT, read_token(&mut stream)).await {
+                    Ok(Ok(t)) if bool::from(t.as_slice().ct_eq(token.as_bytes())) => {}
+                    Ok(Ok(_)) => {
+                        log::warn!("spectator {} sent the wrong token", peer_addr);
+                        return;
+                    }
+                    Ok(Err(e)) => {
+                        log::warn!("failed to read token from spectator {}: {}", peer_addr, e);
+                        return;
+                    }
+                    Err(_) => {
+                        log::warn!("spectator {} did not send a token in time", peer_addr);
+                        return;
+                    }
+                }
+
+                // The backlog is taken and the spectator subscribed under the same lock, so no frame is missed or sent twice: anything sent while the backlog is being written queues up in the channel.
+                let (tx, mut rx) = tokio::sync::mpsc::channel(MAX_PENDING_FRAMES);
+                let backlog = {
+                    let mut inner = inner.lock();
+                    inner.spectators.push(tx);
+                    inner.backlog.clone()
+                };
+
+                if let Err(e) = stream.write_all(&backlog).await {
+                    log::info!("spectator {} disconnected: {}", peer_addr, e);
+                    return;
+                }
+
+                while let Some(buf) = rx.recv().await {
+                    if let Err(e) = stream.write_all(&buf).await {
+                        log::info!("spectator {} disconnected: {}", peer_addr, e);
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    pub fn start_round(&self, local_player_index: u8, state: &mgba::state::State) {
+        let mut inner = self.inner.lock();
+        inner.backlog.clear();
+        inner.local_player_index = local_player_index;
+
+        let mut payload = Vec::with_capacity(1 + state.as_slice().len());
+        payload.push(local_player_index);
+        payload.extend_from_slice(state.as_slice());
+        inner.send_frame(FRAME_KIND_ROUND_START, &payload);
+    }
+
+    pub fn send_input(&self, ip: &input::Pair<input::Input>) {
+        let mut inner = self.inner.lock();
+        let mut payload = vec![];
+        replay::write_input_pair(&mut payload, inner.local_player_index, ip).expect("write input");
+        inner.send_frame(replay::FRAME_KIND_INPUT, &payload);
+    }
+
+    pub fn end_round(&self) {
+        let mut inner = self.inner.lock();
+        inner.send_frame(FRAME_KIND_ROUND_END, &[]);
+        inner.backlog.clear();
+    }
+}
+
+pub struct Receiver<R> {
+    r: R,
+    local_player_index: u8,
+}
+
+impl<R: std::io::Read> Receiver<R> {
+    pub fn new(r: R) -> Self {
+        Receiver {
+            r,
+            local_player_index: 0,
+        }
+    }
+
+    pub fn next_event(&mut self) -> std::io::Result<Option<Event>> {
+        loop {
+            let (kind, payload) = match replay::read_frame(&mut self.r)? {
+                Some(frame) => frame,
+                None => {
+                    return Ok(None);
+                }
+            };
+
+            match kind {
+                FRAME_KIND_ROUND_START => {
+                    let (&local_player_index, state) = payload.split_first().ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidData, "empty round start")
+                    })?;
+                    self.local_player_index = local_player_index;
+                    return Ok(Some(Event::RoundStarted {
+                        local_player_index,
+                        state: mgba::state::State::from_slice(state),
+                    }));
+                }
+                replay::FRAME_KIND_INPUT => {
+                    return Ok(Some(Event::Input(replay::read_input_pair(
+                        payload.as_slice(),
+                        self.local_player_index,
+                    )?)));
+                }
+                FRAME_KIND_ROUND_END => {
+                    return Ok(Some(Event::RoundEnded));
+                }
+                _ => {
+                    log::warn!("skipping unknown spectator frame kind: {}", kind);
+                }
+            }
+        }
+    }
+}
@@ -5,6 +5,9 @@ use tango_matchmaking::server;
 struct Config {
     #[envconfig(from = "LISTEN_ADDR", default = "[::]:1984")]
     pub listen_addr: String,
+
+    #[envconfig(from = "SESSION_EXPIRY_SECS", default = "300")]
+    pub session_expiry_secs: u64,
 }
 
 #[tokio::main]
@@ -20,7 +23,10 @@ async fn main() -> anyhow::Result<()> {
     );
     let config = Config::init_from_env().unwrap();
     let listener = tokio::net::TcpListener::bind(config.listen_addr).await?;
-    let mut server = server::Server::new(listener);
+    let mut server = server::Server::new(
+        listener,
+        std::time::Duration::from_secs(config.session_expiry_secs),
+    );
     server.run().await;
     Ok(())
 }
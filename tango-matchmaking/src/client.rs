@@ -2,24 +2,81 @@ use super::protocol;
 use futures_util::SinkExt;
 use futures_util::TryStreamExt;
 
+type Stream =
+    tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
+
+// The signalling connection, kept open after the session descriptions have been exchanged so ICE candidates can keep trickling in both directions.
+pub struct Session {
+    stream: Stream,
+}
+
+async fn send_ice_candidate(
+    stream: &mut Stream,
+    cand: datachannel_wrapper::IceCandidate,
+) -> Result<(), anyhow::Error> {
+    stream
+        .send(tokio_tungstenite::tungstenite::Message::Binary(
+            protocol::Packet::ICECandidate(protocol::ICECandidate {
+                candidate: cand.candidate,
+                mid: cand.mid,
+            })
+            .serialize()?,
+        ))
+        .await?;
+    Ok(())
+}
+
+async fn receive_packet(stream: &mut Stream) -> Result<Option<protocol::Packet>, anyhow::Error> {
+    let raw = if let Some(raw) = stream.try_next().await? {
+        raw
+    } else {
+        return Ok(None);
+    };
+
+    if let tokio_tungstenite::tungstenite::Message::Binary(d) = raw {
+        Ok(Some(protocol::Packet::deserialize(&d)?))
+    } else {
+        anyhow::bail!("invalid packet");
+    }
+}
+
+fn add_remote_candidate(
+    peer_conn: &mut datachannel_wrapper::PeerConnection,
+    ice_candidate: protocol::ICECandidate,
+) {
+    // A bad candidate shouldn't take the whole connection down: ICE can still succeed with the others.
+    if let Err(e) = peer_conn.add_remote_candidate(datachannel_wrapper::IceCandidate {
+        candidate: ice_candidate.candidate,
+        mid: ice_candidate.mid,
+    }) {
+        log::warn!("failed to add remote ice candidate: {}", e);
+    }
+}
+
 pub async fn connect(
     addr: &str,
     peer_conn: &mut datachannel_wrapper::PeerConnection,
-    mut signal_rx: tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionSignal>,
+    signal_rx: &mut tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionSignal>,
     session_id: &str,
-) -> Result<(), anyhow::Error>
-where
-{
+) -> Result<Session, anyhow::Error> {
     let (mut stream, _) = tokio_tungstenite::connect_async(addr).await?;
 
     log::info!("negotiation started");
 
+    // We don't know whether our offer will be used until the server tells us which side we are: if it's discarded, so are the candidates gathered for it. Until then, they're held here.
+    let mut pending_local_candidates = vec![];
     loop {
-        if let Some(datachannel_wrapper::PeerConnectionSignal::GatheringStateChange(
-            datachannel_wrapper::GatheringState::Complete,
-        )) = signal_rx.recv().await
-        {
-            break;
+        match signal_rx.recv().await {
+            Some(datachannel_wrapper::PeerConnectionSignal::SessionDescription(_)) => {
+                break;
+            }
+            Some(datachannel_wrapper::PeerConnectionSignal::IceCandidate(cand)) => {
+                pending_local_candidates.push(cand);
+            }
+            Some(_) => {}
+            None => {
+                anyhow::bail!("peer connection signal channel closed");
+            }
         }
     }
 
@@ -36,38 +93,29 @@ where
         .await?;
     log::info!("negotiation start sent");
 
+    // Remote candidates can't be added until we have a remote description.
+    let mut pending_remote_candidates = vec![];
+
     loop {
         tokio::select! {
             signal_msg = signal_rx.recv() => {
-                let cand = if let Some(datachannel_wrapper::PeerConnectionSignal::IceCandidate(cand)) = signal_msg {
-                    cand
-                } else {
-                    anyhow::bail!("ice candidate not received")
-                };
-
-                stream
-                    .send(tokio_tungstenite::tungstenite::Message::Binary(
-                        protocol::Packet::ICECandidate(protocol::ICECandidate {
-                            candidate: cand.candidate,
-                            mid: cand.mid,
-                        })
-                        .serialize()?,
-                    ))
-                    .await?;
+                match signal_msg {
+                    Some(datachannel_wrapper::PeerConnectionSignal::IceCandidate(cand)) => {
+                        pending_local_candidates.push(cand);
+                    }
+                    Some(_) => {}
+                    None => {
+                        anyhow::bail!("peer connection signal channel closed");
+                    }
+                }
             }
-            ws_msg = stream.try_next() => {
-                let raw = if let Some(raw) = ws_msg? {
-                    raw
+            packet = receive_packet(&mut stream) => {
+                let packet = if let Some(packet) = packet? {
+                    packet
                 } else {
                     anyhow::bail!("stream ended early");
                 };
 
-                let packet = if let tokio_tungstenite::tungstenite::Message::Binary(d) = raw {
-                    protocol::Packet::deserialize(&d)?
-                } else {
-                    anyhow::bail!("invalid packet");
-                };
-
                 match packet {
                     protocol::Packet::Start(_) => {
                         anyhow::bail!("unexpected start");
@@ -75,6 +123,14 @@ where
                     protocol::Packet::Offer(offer) => {
                         log::info!("received an offer, this is the polite side. rolling back our local description and switching to answer");
 
+                        // Everything gathered so far was for the offer we're about to roll back, including anything still queued up. Nothing else in the queue matters yet either: we can't be connected before we've answered.
+                        while signal_rx.try_recv().is_ok() {}
+                        log::info!(
+                            "dropping {} local ice candidates gathered for our offer",
+                            pending_local_candidates.len()
+                        );
+                        pending_local_candidates.clear();
+
                         peer_conn.set_local_description(datachannel_wrapper::SdpType::Rollback)?;
                         peer_conn.set_remote_description(datachannel_wrapper::SessionDescription {
                             sdp_type: datachannel_wrapper::SdpType::Offer,
@@ -100,41 +156,103 @@ where
                             sdp_type: datachannel_wrapper::SdpType::Answer,
                             sdp: datachannel_wrapper::parse_sdp(&answer.sdp.to_string(), false)?,
                         })?;
+
+                        // Our offer is the one being used, so the candidates gathered for it are good to go.
+                        for cand in pending_local_candidates.drain(..) {
+                            send_ice_candidate(&mut stream, cand).await?;
+                        }
                         break;
                     }
-                    protocol::Packet::ICECandidate(_ice_candidate) => {
-                        anyhow::bail!("ice candidates not supported");
+                    protocol::Packet::ICECandidate(ice_candidate) => {
+                        pending_remote_candidates.push(ice_candidate);
+                    }
+                    protocol::Packet::Error(err) => {
+                        return Err(err.into());
                     }
                 }
             }
         };
     }
 
-    stream.close(None).await?;
+    for ice_candidate in pending_remote_candidates {
+        add_remote_candidate(peer_conn, ice_candidate);
+    }
 
-    loop {
-        match signal_rx.recv().await {
-            Some(signal) => match signal {
-                datachannel_wrapper::PeerConnectionSignal::ConnectionStateChange(c) => match c {
-                    datachannel_wrapper::ConnectionState::Connected => {
-                        break;
-                    }
-                    datachannel_wrapper::ConnectionState::Disconnected => {
-                        anyhow::bail!("peer connection unexpectedly disconnected");
-                    }
-                    datachannel_wrapper::ConnectionState::Failed => {
-                        anyhow::bail!("peer connection failed");
+    Ok(Session { stream })
+}
+
+impl Session {
+    // Keeps exchanging ICE candidates until the peer connection is up, then closes the signalling connection.
+    pub async fn wait_for_connected(
+        mut self,
+        peer_conn: &mut datachannel_wrapper::PeerConnection,
+        signal_rx: &mut tokio::sync::mpsc::Receiver<datachannel_wrapper::PeerConnectionSignal>,
+    ) -> Result<(), anyhow::Error> {
+        // The remote hangs up as soon as it's connected, which may be a little before we are: that's fine, ICE just carries on with the candidates we already have.
+        let mut stream_open = true;
+        loop {
+            tokio::select! {
+                signal_msg = signal_rx.recv() => {
+                    match signal_msg {
+                        Some(datachannel_wrapper::PeerConnectionSignal::IceCandidate(cand)) => {
+                            if stream_open {
+                                if let Err(e) = send_ice_candidate(&mut self.stream, cand).await {
+                                    log::warn!("failed to send ice candidate: {}", e);
+                                    stream_open = false;
+                                }
+                            }
+                        }
+                        Some(datachannel_wrapper::PeerConnectionSignal::ConnectionStateChange(c)) => match c {
+                            datachannel_wrapper::ConnectionState::Connected => {
+                                break;
+                            }
+                            datachannel_wrapper::ConnectionState::Disconnected => {
+                                anyhow::bail!("peer connection unexpectedly disconnected");
+                            }
+                            datachannel_wrapper::ConnectionState::Failed => {
+                                anyhow::bail!("peer connection failed");
+                            }
+                            datachannel_wrapper::ConnectionState::Closed => {
+                                anyhow::bail!("peer connection unexpectedly closed");
+                            }
+                            _ => {}
+                        },
+                        Some(_) => {}
+                        None => {
+                            anyhow::bail!("peer connection signal channel closed");
+                        }
                     }
-                    datachannel_wrapper::ConnectionState::Closed => {
-                        anyhow::bail!("peer connection unexpectedly closed");
+                }
+                packet = receive_packet(&mut self.stream), if stream_open => {
+                    match packet {
+                        Ok(Some(protocol::Packet::ICECandidate(ice_candidate))) => {
+                            add_remote_candidate(peer_conn, ice_candidate);
+                        }
+                        Ok(Some(protocol::Packet::Error(err))) => {
+                            return Err(err.into());
+                        }
+                        Ok(Some(p)) => {
+                            anyhow::bail!("unexpected packet: {:?}", p);
+                        }
+                        Ok(None) => {
+                            log::info!("signalling stream closed by remote, waiting for ice to finish connecting");
+                            stream_open = false;
+                        }
+                        Err(e) => {
+                            log::warn!("signalling stream failed, waiting for ice to finish connecting: {}", e);
+                            stream_open = false;
+                        }
                     }
-                    _ => {}
-                },
-                _ => {}
-            },
-            None => unreachable!(),
+                }
+            };
         }
-    }
 
-    Ok(())
+        if stream_open {
+            if let Err(e) = self.stream.close(None).await {
+                log::warn!("failed to close signalling stream: {}", e);
+            }
+        }
+
+        Ok(())
+    }
 }
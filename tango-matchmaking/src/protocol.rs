@@ -1,6 +1,6 @@
 use bincode::Options;
 
-pub const VERSION: u8 = 0x0d;
+pub const VERSION: u8 = 0x0f;
 
 lazy_static! {
     static ref BINCODE_OPTIONS: bincode::config::WithOtherLimit<
@@ -20,6 +20,7 @@ pub enum Packet {
     Offer(Offer),
     Answer(Answer),
     ICECandidate(ICECandidate),
+    Error(Error),
 }
 
 impl Packet {
@@ -54,3 +55,29 @@ pub struct ICECandidate {
     pub candidate: String,
     pub mid: String,
 }
+
+// Sent by the server right before it hangs up on a client.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub enum Error {
+    ProtocolVersionMismatch { server_version: u8 },
+    SessionFull,
+    SessionExpired,
+    UnexpectedPacket,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::ProtocolVersionMismatch { server_version } => write!(
+                f,
+                "protocol version mismatch: server speaks {:02x}",
+                server_version
+            ),
+            Error::SessionFull => write!(f, "session full"),
+            Error::SessionExpired => write!(f, "session expired"),
+            Error::UnexpectedPacket => write!(f, "unexpected packet"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -2,8 +2,11 @@ use super::protocol;
 use futures_util::{SinkExt, StreamExt, TryStreamExt};
 
 struct Session {
-    num_clients: usize,
     offer_sdp: String,
+    // Wakes the first client up when the second one joins, so it stops waiting on the expiry.
+    filled: std::sync::Arc<tokio::sync::Notify>,
+    // ICE candidates from the first client, held until the second client joins.
+    pending_ice_candidates: Vec<protocol::ICECandidate>,
     sinks: Vec<
         futures_util::stream::SplitSink<
             tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
@@ -19,6 +22,22 @@ pub struct Server {
             std::collections::HashMap<String, std::sync::Arc<tokio::sync::Mutex<Session>>>,
         >,
     >,
+    session_expiry: std::time::Duration,
+}
+
+async fn send_error(
+    sink: &mut futures_util::stream::SplitSink<
+        tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>,
+        tokio_tungstenite::tungstenite::Message,
+    >,
+    err: protocol::Error,
+) -> anyhow::Result<()> {
+    sink.send(tokio_tungstenite::tungstenite::Message::Binary(
+        protocol::Packet::Error(err).serialize()?,
+    ))
+    .await?;
+    sink.close().await?;
+    Ok(())
 }
 
 async fn handle_connection(
@@ -29,123 +48,232 @@ async fn handle_connection(
     >,
     raw_stream: tokio::net::TcpStream,
     addr: std::net::SocketAddr,
+    session_expiry: std::time::Duration,
 ) -> anyhow::Result<()> {
     let (tx, mut rx) = tokio_tungstenite::accept_async(raw_stream).await?.split();
     let mut tx = Some(tx);
-    let session_id = std::sync::Arc::new(tokio::sync::Mutex::new(None));
-    let mut session = None;
+    let mut session_id = None;
+    let mut session: Option<std::sync::Arc<tokio::sync::Mutex<Session>>> = None;
     let mut me: usize = 0;
 
-    let r = {
-        let sessions = sessions.clone();
-        let session_id = session_id.clone();
-        (move || async move {
-            loop {
-                let msg = match rx.try_next().await? {
-                    Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
-                        protocol::Packet::deserialize(&d)?
+    // Clients that haven't been paired up by then are dropped, so abandoned sessions don't pile up. Once paired, the expiry no longer applies.
+    let expires_at = tokio::time::Instant::now() + session_expiry;
+
+    let r = async {
+        loop {
+            let waiting_for_peer = match session.as_ref() {
+                Some(session) => {
+                    let session = session.lock().await;
+                    if session.sinks.len() < 2 {
+                        Some(Some(session.filled.clone()))
+                    } else {
+                        None
                     }
-                    Some(_) => {
-                        anyhow::bail!("unexpected message");
+                }
+                None => Some(None),
+            };
+
+            let raw = match waiting_for_peer {
+                Some(filled) => {
+                    tokio::select! {
+                        raw = rx.try_next() => raw?,
+                        _ = async {
+                            match filled {
+                                Some(filled) => filled.notified().await,
+                                None => std::future::pending().await,
+                            }
+                        } => {
+                            continue;
+                        }
+                        _ = tokio::time::sleep_until(expires_at) => {
+                            anyhow::bail!(protocol::Error::SessionExpired);
+                        }
                     }
-                    None => {
-                        break;
+                }
+                None => rx.try_next().await?,
+            };
+
+            let msg = match raw {
+                Some(tokio_tungstenite::tungstenite::Message::Binary(d)) => {
+                    match protocol::Packet::deserialize(&d) {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            log::warn!("failed to deserialize message from {}: {}", addr, e);
+                            anyhow::bail!(protocol::Error::UnexpectedPacket);
+                        }
                     }
-                };
-                log::debug!("received message from {}: {:?}", addr, msg);
-                match msg {
-                    protocol::Packet::Start(start) => {
-                        let mut sessions = sessions.lock().await;
-                        session = Some(
-                            sessions
-                                .entry(start.session_id.clone())
-                                .or_insert_with(|| {
-                                    std::sync::Arc::new(tokio::sync::Mutex::new(Session {
-                                        num_clients: 0,
-                                        offer_sdp: start.offer_sdp.clone(),
-                                        sinks: vec![],
-                                    }))
-                                })
-                                .clone(),
+                }
+                Some(tokio_tungstenite::tungstenite::Message::Close(_)) | None => {
+                    break;
+                }
+                Some(tokio_tungstenite::tungstenite::Message::Ping(_))
+                | Some(tokio_tungstenite::tungstenite::Message::Pong(_)) => {
+                    continue;
+                }
+                Some(_) => {
+                    anyhow::bail!(protocol::Error::UnexpectedPacket);
+                }
+            };
+            log::debug!("received message from {}: {:?}", addr, msg);
+            match msg {
+                protocol::Packet::Start(start) => {
+                    if session.is_some() {
+                        anyhow::bail!(protocol::Error::UnexpectedPacket);
+                    }
+
+                    if start.protocol_version != protocol::VERSION {
+                        log::warn!(
+                            "client {} has protocol version {:02x}, expected {:02x}",
+                            addr,
+                            start.protocol_version,
+                            protocol::VERSION
                         );
+                        anyhow::bail!(protocol::Error::ProtocolVersionMismatch {
+                            server_version: protocol::VERSION,
+                        });
+                    }
+
+                    let mut sessions = sessions.lock().await;
+                    let s = sessions
+                        .entry(start.session_id.clone())
+                        .or_insert_with(|| {
+                            std::sync::Arc::new(tokio::sync::Mutex::new(Session {
+                                offer_sdp: start.offer_sdp.clone(),
+                                filled: std::sync::Arc::new(tokio::sync::Notify::new()),
+                                pending_ice_candidates: vec![],
+                                sinks: vec![],
+                            }))
+                        })
+                        .clone();
+
+                    let mut s_guard = s.lock().await;
+                    if s_guard.sinks.len() >= 2 {
+                        anyhow::bail!(protocol::Error::SessionFull);
+                    }
+
+                    session_id = Some(start.session_id.clone());
+                    let offer_sdp = s_guard.offer_sdp.to_string();
 
-                        let session = session.as_ref().unwrap();
-                        let mut session = session.lock().await;
-                        session.num_clients += 1;
-                        *session_id.lock().await = Some(start.session_id.clone());
-                        let offer_sdp = session.offer_sdp.to_string();
+                    me = s_guard.sinks.len();
+                    s_guard.sinks.push(tx.take().unwrap());
 
-                        me = session.sinks.len();
-                        session.sinks.push(tx.take().unwrap());
+                    if me == 1 {
+                        // There's only ever the one client waiting on this, so the permit is kept even if it isn't waiting right now.
+                        s_guard.filled.notify_one();
+
+                        s_guard.sinks[me]
+                            .send(tokio_tungstenite::tungstenite::Message::Binary(
+                                protocol::Packet::Offer(protocol::Offer { sdp: offer_sdp })
+                                    .serialize()?,
+                            ))
+                            .await?;
 
-                        if me == 1 {
-                            session.sinks[me]
+                        for ice_candidate in std::mem::take(&mut s_guard.pending_ice_candidates) {
+                            s_guard.sinks[me]
                                 .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                    protocol::Packet::Offer(protocol::Offer { sdp: offer_sdp })
-                                        .serialize()?,
+                                    protocol::Packet::ICECandidate(ice_candidate).serialize()?,
                                 ))
                                 .await?;
                         }
                     }
-                    protocol::Packet::Offer(_) => {
-                        anyhow::bail!(
-                            "received offer from client: only the server may send offers"
-                        );
-                    }
-                    protocol::Packet::Answer(answer) => {
-                        let session = match session.as_ref() {
-                            Some(session) => session,
-                            None => {
-                                anyhow::bail!("no session active");
-                            }
-                        };
-                        let mut session = session.lock().await;
-                        session.sinks[0]
-                            .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                protocol::Packet::Answer(protocol::Answer { sdp: answer.sdp })
-                                    .serialize()?,
-                            ))
-                            .await?;
+
+                    drop(s_guard);
+                    session = Some(s);
+                }
+                protocol::Packet::Offer(_) => {
+                    log::warn!(
+                        "received offer from client {}: only the server may send offers",
+                        addr
+                    );
+                    anyhow::bail!(protocol::Error::UnexpectedPacket);
+                }
+                protocol::Packet::Answer(answer) => {
+                    let session = match session.as_ref() {
+                        Some(session) => session,
+                        None => {
+                            anyhow::bail!(protocol::Error::UnexpectedPacket);
+                        }
+                    };
+
+                    // Only the side that received the offer may answer it.
+                    if me != 1 {
+                        anyhow::bail!(protocol::Error::UnexpectedPacket);
                     }
-                    protocol::Packet::ICECandidate(ice_candidate) => {
-                        let session = match session.as_ref() {
-                            Some(session) => session,
-                            None => {
-                                anyhow::bail!("no session active");
-                            }
-                        };
-                        let mut session = session.lock().await;
-                        session.sinks[1 - me]
-                            .send(tokio_tungstenite::tungstenite::Message::Binary(
-                                protocol::Packet::ICECandidate(protocol::ICECandidate {
-                                    candidate: ice_candidate.candidate,
-                                    mid: ice_candidate.mid,
-                                })
+
+                    let mut session = session.lock().await;
+                    session.sinks[0]
+                        .send(tokio_tungstenite::tungstenite::Message::Binary(
+                            protocol::Packet::Answer(protocol::Answer { sdp: answer.sdp })
                                 .serialize()?,
-                            ))
-                            .await?;
+                        ))
+                        .await?;
+                }
+                protocol::Packet::ICECandidate(ice_candidate) => {
+                    let session = match session.as_ref() {
+                        Some(session) => session,
+                        None => {
+                            anyhow::bail!(protocol::Error::UnexpectedPacket);
+                        }
+                    };
+                    let mut session = session.lock().await;
+                    if session.sinks.len() < 2 {
+                        session.pending_ice_candidates.push(ice_candidate);
+                        continue;
                     }
+                    // The other side hangs up as soon as it's connected, so it may no longer want our candidates.
+                    if let Err(e) = session.sinks[1 - me]
+                        .send(tokio_tungstenite::tungstenite::Message::Binary(
+                            protocol::Packet::ICECandidate(protocol::ICECandidate {
+                                candidate: ice_candidate.candidate,
+                                mid: ice_candidate.mid,
+                            })
+                            .serialize()?,
+                        ))
+                        .await
+                    {
+                        log::debug!("failed to relay ice candidate from {}: {}", addr, e);
+                    }
+                }
+                protocol::Packet::Error(_) => {
+                    log::warn!(
+                        "received error from client {}: only the server may send errors",
+                        addr
+                    );
+                    anyhow::bail!(protocol::Error::UnexpectedPacket);
                 }
             }
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+
+    if let Some(err) = r
+        .as_ref()
+        .err()
+        .and_then(|e| e.downcast_ref::<protocol::Error>())
+        .cloned()
+    {
+        let sent = if let Some(tx) = tx.as_mut() {
+            send_error(tx, err).await
+        } else if let Some(session) = session.as_ref() {
+            send_error(&mut session.lock().await.sinks[me], err).await
+        } else {
             Ok(())
-        })()
-        .await
-    };
+        };
+        if let Err(e) = sent {
+            log::warn!("failed to send error to client {}: {}", addr, e);
+        }
+    }
 
-    if let Some(session_id) = &*session_id.lock().await {
+    if let (Some(session_id), Some(session)) = (session_id, session) {
         let mut sessions = sessions.lock().await;
-        let should_delete = {
-            if let Some(session) = sessions.get(session_id) {
-                let mut session = session.lock().await;
-                session.num_clients -= 1;
-                true
-            } else {
-                false
-            }
-        };
 
-        if should_delete {
-            sessions.remove(session_id);
+        // The session may have already been torn down and replaced by a new one with the same ID, which isn't ours to remove.
+        if sessions
+            .get(&session_id)
+            .map_or(false, |s| std::sync::Arc::ptr_eq(s, &session))
+        {
+            sessions.remove(&session_id);
         }
     }
 
@@ -153,20 +281,22 @@ async fn handle_connection(
 }
 
 impl Server {
-    pub fn new(listener: tokio::net::TcpListener) -> Server {
+    pub fn new(listener: tokio::net::TcpListener, session_expiry: std::time::Duration) -> Server {
         Server {
             listener,
             sessions: std::sync::Arc::new(
                 tokio::sync::Mutex::new(std::collections::HashMap::new()),
             ),
+            session_expiry,
         }
     }
 
     pub async fn run(&mut self) {
         while let Ok((stream, addr)) = self.listener.accept().await {
             let sessions = self.sessions.clone();
+            let session_expiry = self.session_expiry;
             tokio::spawn(async move {
-                if let Err(e) = handle_connection(sessions, stream, addr).await {
+                if let Err(e) = handle_connection(sessions, stream, addr, session_expiry).await {
                     log::warn!("client {} disconnected with error: {}", addr, e);
                 }
             });